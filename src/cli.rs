@@ -0,0 +1,53 @@
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(name = "HKCM", version, about = "Hollow Knight Controller Masher")]
+pub struct Cli {
+    /// Settings file to use instead of HKCM_settings.json in the data directory
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Directory for the log file and default settings file
+    #[arg(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Profile to switch to before starting, it stays active in the settings file
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Read controllers from /dev/input instead of through SDL (Linux only)
+    #[arg(long)]
+    pub evdev: bool,
+
+    /// Run without opening a window
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Print the current settings as JSON and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Overwrite the settings file with the default settings and exit
+    #[arg(long)]
+    pub reset_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write the mashing triggers to the settings file and exit
+    SetTriggers {
+        /// SDL gamepad button names such as `west south east`, `lefttrigger`, `righttrigger`
+        /// or stick directions such as `leftstick_up`
+        #[arg(required = true)]
+        buttons: Vec<String>,
+    },
+}
@@ -0,0 +1,550 @@
+use crate::settings::{ControllerInfo, Input};
+#[cfg(target_os = "linux")]
+use crate::virtual_pad::is_virtual_device;
+use sdl3::gamepad;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::SystemTime;
+#[cfg(target_os = "linux")]
+use {
+    evdev::{AbsoluteAxisType, InputEventKind, Key},
+    log::{info, warn},
+    std::collections::HashSet,
+    std::path::PathBuf,
+    std::sync::atomic::{AtomicU32, Ordering},
+    std::sync::mpsc::Sender,
+    std::sync::Mutex,
+    std::thread,
+    std::time::Duration,
+};
+
+/// `held_buttons` entry shared by every keyboard and mouse, so a chord can mix keys from both
+pub const KEYBOARD_ID: u32 = u32::MAX;
+
+/// Gamepads read through evdev are numbered from here, well clear of SDL's joystick ids
+#[cfg(target_os = "linux")]
+const FIRST_GAMEPAD_ID: u32 = 0x4000_0000;
+
+/// How often to look for devices plugged in after startup
+#[cfg(target_os = "linux")]
+const RESCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `value` of a key event that is auto-repeating rather than pressed or released
+#[cfg(target_os = "linux")]
+const KEY_REPEAT: i32 = 2;
+
+/// Gamepad buttons in the kernel's layout, see `Documentation/input/gamepad.rst`
+#[cfg(target_os = "linux")]
+static GAMEPAD_BUTTONS: &[(Key, Input)] = &[
+    (Key::BTN_SOUTH, Input::Button(gamepad::Button::South)),
+    (Key::BTN_EAST, Input::Button(gamepad::Button::East)),
+    (Key::BTN_NORTH, Input::Button(gamepad::Button::North)),
+    (Key::BTN_WEST, Input::Button(gamepad::Button::West)),
+    (Key::BTN_TL, Input::Button(gamepad::Button::LeftShoulder)),
+    (Key::BTN_TR, Input::Button(gamepad::Button::RightShoulder)),
+    // Only for triggers without an axis, see `TRIGGER_AXES`
+    (Key::BTN_TL2, Input::LeftTrigger),
+    (Key::BTN_TR2, Input::RightTrigger),
+    (Key::BTN_SELECT, Input::Button(gamepad::Button::Back)),
+    (Key::BTN_START, Input::Button(gamepad::Button::Start)),
+    (Key::BTN_MODE, Input::Button(gamepad::Button::Guide)),
+    (Key::BTN_THUMBL, Input::Button(gamepad::Button::LeftStick)),
+    (Key::BTN_THUMBR, Input::Button(gamepad::Button::RightStick)),
+    (Key::BTN_DPAD_UP, Input::Button(gamepad::Button::DPadUp)),
+    (Key::BTN_DPAD_DOWN, Input::Button(gamepad::Button::DPadDown)),
+    (Key::BTN_DPAD_LEFT, Input::Button(gamepad::Button::DPadLeft)),
+    (
+        Key::BTN_DPAD_RIGHT,
+        Input::Button(gamepad::Button::DPadRight),
+    ),
+];
+
+/// Trigger axes and the trigger input their digital button also reports
+#[cfg(target_os = "linux")]
+static TRIGGER_AXES: &[(AbsoluteAxisType, Input)] = &[
+    (AbsoluteAxisType::ABS_Z, Input::LeftTrigger),
+    (AbsoluteAxisType::ABS_RZ, Input::RightTrigger),
+];
+
+/// Analog axes and the gamepad axis they stand for
+#[cfg(target_os = "linux")]
+static GAMEPAD_AXES: &[(AbsoluteAxisType, gamepad::Axis)] = &[
+    (AbsoluteAxisType::ABS_X, gamepad::Axis::LeftX),
+    (AbsoluteAxisType::ABS_Y, gamepad::Axis::LeftY),
+    (AbsoluteAxisType::ABS_RX, gamepad::Axis::RightX),
+    (AbsoluteAxisType::ABS_RY, gamepad::Axis::RightY),
+    (AbsoluteAxisType::ABS_Z, gamepad::Axis::TriggerLeft),
+    (AbsoluteAxisType::ABS_RZ, gamepad::Axis::TriggerRight),
+];
+
+// Only ever constructed by the Linux readers
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[derive(Clone, PartialEq, Debug)]
+pub enum EvdevChange {
+    Button {
+        input: Input,
+        pressed: bool,
+    },
+    /// An analog axis moved. Sticks go from -1 to 1 and triggers from 0 to 1.
+    Axis {
+        axis: gamepad::Axis,
+        value: f32,
+    },
+    /// A gamepad was opened. Keyboards all share `KEYBOARD_ID` and aren't announced.
+    Connected {
+        info: ControllerInfo,
+    },
+    Disconnected,
+}
+
+// Only ever constructed by the Linux readers
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct EvdevEvent {
+    pub device_id: u32,
+    pub change: EvdevChange,
+    /// When the kernel saw the input, which can be a little before HKCM reads it
+    pub timestamp: SystemTime,
+}
+
+/// Called from the reading thread with every change a gamepad makes, as soon as it is read
+pub type Forwarder = Arc<dyn Fn(u32, &EvdevChange) + Send + Sync>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum DeviceKind {
+    /// Keyboards and mice, reporting keys by their input event code
+    Keyboard,
+    Gamepad,
+}
+
+/// Reads input devices straight from `/dev/input`, so they work while the game has focus
+/// and without SDL. Nothing is opened until the reader is first enabled.
+pub struct EvdevReader {
+    kind: DeviceKind,
+    forwarder: Option<Forwarder>,
+    events: Option<Receiver<EvdevEvent>>,
+}
+
+impl EvdevReader {
+    pub fn keyboards() -> Self {
+        EvdevReader {
+            kind: DeviceKind::Keyboard,
+            forwarder: None,
+            events: None,
+        }
+    }
+
+    /// With a `forwarder` every gamepad is grabbed, so its inputs only reach other
+    /// programs through whatever the forwarder does with them
+    pub fn gamepads(forwarder: Option<Forwarder>) -> Self {
+        EvdevReader {
+            kind: DeviceKind::Gamepad,
+            forwarder,
+            events: None,
+        }
+    }
+
+    /// Returns the presses and releases since the last call.
+    /// Events that arrive while `enabled` is false are dropped.
+    pub fn poll(&mut self, enabled: bool) -> Vec<EvdevEvent> {
+        if enabled && self.events.is_none() {
+            self.events = Some(start_reading(self.kind, self.forwarder.clone()));
+        }
+
+        let Some(events) = &self.events else {
+            return Vec::new();
+        };
+        let events: Vec<EvdevEvent> = events.try_iter().collect();
+        if enabled {
+            events
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_reading(kind: DeviceKind, _forwarder: Option<Forwarder>) -> Receiver<EvdevEvent> {
+    match kind {
+        DeviceKind::Keyboard => log::warn!("Keyboard triggers are only supported on Linux"),
+        DeviceKind::Gamepad => {
+            log::warn!("Reading controllers through evdev is only supported on Linux")
+        }
+    }
+    // The sender is dropped straight away, so nothing is ever received
+    mpsc::channel().1
+}
+
+#[cfg(target_os = "linux")]
+fn is_device_kind(device: &evdev::Device, kind: DeviceKind) -> bool {
+    let id = device.input_id();
+    if is_virtual_device(device.name(), Some(id.vendor()), Some(id.product())) {
+        return false;
+    }
+
+    device.supported_keys().is_some_and(|keys| match kind {
+        DeviceKind::Keyboard => keys.contains(Key::KEY_A) || keys.contains(Key::BTN_LEFT),
+        DeviceKind::Gamepad => keys.contains(Key::BTN_SOUTH),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn start_reading(kind: DeviceKind, forwarder: Option<Forwarder>) -> Receiver<EvdevEvent> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let opened: Arc<Mutex<HashSet<PathBuf>>> = Arc::new(Mutex::new(HashSet::new()));
+        let next_gamepad_id = AtomicU32::new(FIRST_GAMEPAD_ID);
+        let mut warned = false;
+
+        loop {
+            for (path, device) in evdev::enumerate() {
+                if opened.lock().unwrap().contains(&path) || !is_device_kind(&device, kind) {
+                    continue;
+                }
+
+                info!(
+                    "Reading input from {} ({})",
+                    device.name().unwrap_or("unnamed device"),
+                    path.display()
+                );
+                opened.lock().unwrap().insert(path.clone());
+
+                let device_id = match kind {
+                    DeviceKind::Keyboard => KEYBOARD_ID,
+                    DeviceKind::Gamepad => next_gamepad_id.fetch_add(1, Ordering::SeqCst),
+                };
+                let sender = sender.clone();
+                let opened = Arc::clone(&opened);
+                let forwarder = forwarder.clone();
+                thread::spawn(move || {
+                    read_device(device, device_id, kind, &sender, forwarder.as_ref());
+                    opened.lock().unwrap().remove(&path);
+                });
+            }
+
+            if !warned && opened.lock().unwrap().is_empty() {
+                match kind {
+                    DeviceKind::Keyboard => {
+                        warn!("No keyboards found, HKCM needs permission to read /dev/input/event*")
+                    }
+                    DeviceKind::Gamepad => warn!(
+                        "No controllers found, HKCM needs permission to read /dev/input/event*"
+                    ),
+                }
+                warned = true;
+            }
+            thread::sleep(RESCAN_INTERVAL);
+        }
+    });
+
+    receiver
+}
+
+/// Scales a raw axis value into the range used by `EvdevChange::Axis`
+#[cfg(target_os = "linux")]
+fn normalize_axis(axis: gamepad::Axis, value: i32, range: (i32, i32)) -> f32 {
+    let (minimum, maximum) = range;
+    if maximum <= minimum {
+        return 0.0;
+    }
+    let fraction = (value - minimum) as f32 / (maximum - minimum) as f32;
+    match axis {
+        gamepad::Axis::TriggerLeft | gamepad::Axis::TriggerRight => fraction,
+        _ => fraction * 2.0 - 1.0,
+    }
+}
+
+/// Turns one raw event into the changes it makes.
+/// `axis_ranges` holds the minimum and maximum of each entry in `GAMEPAD_AXES`.
+#[cfg(target_os = "linux")]
+fn decode_event(
+    event: &evdev::InputEvent,
+    kind: DeviceKind,
+    axis_ranges: &[(i32, i32)],
+) -> Vec<EvdevChange> {
+    let button = |input: Input, pressed: bool| EvdevChange::Button { input, pressed };
+    match (event.kind(), kind) {
+        (InputEventKind::Key(_), _) if event.value() == KEY_REPEAT => Vec::new(),
+        (InputEventKind::Key(key), DeviceKind::Keyboard) => Input::from_key_code(key.code())
+            .map(|input| vec![button(input, event.value() != 0)])
+            .unwrap_or_default(),
+        (InputEventKind::Key(key), DeviceKind::Gamepad) => GAMEPAD_BUTTONS
+            .iter()
+            .find(|(gamepad_button, _)| *gamepad_button == key)
+            .map(|(_, input)| vec![button(*input, event.value() != 0)])
+            .unwrap_or_default(),
+        (InputEventKind::AbsAxis(axis), DeviceKind::Gamepad) => {
+            let value = event.value();
+            let hat = |negative: gamepad::Button, positive: gamepad::Button| {
+                vec![
+                    button(Input::Button(negative), value < 0),
+                    button(Input::Button(positive), value > 0),
+                ]
+            };
+            match axis {
+                AbsoluteAxisType::ABS_HAT0X => {
+                    hat(gamepad::Button::DPadLeft, gamepad::Button::DPadRight)
+                }
+                AbsoluteAxisType::ABS_HAT0Y => {
+                    hat(gamepad::Button::DPadUp, gamepad::Button::DPadDown)
+                }
+                _ => GAMEPAD_AXES
+                    .iter()
+                    .zip(axis_ranges)
+                    .find(|((abs_axis, _), _)| *abs_axis == axis)
+                    .map(|((_, gamepad_axis), range)| {
+                        vec![EvdevChange::Axis {
+                            axis: *gamepad_axis,
+                            value: normalize_axis(*gamepad_axis, value, *range),
+                        }]
+                    })
+                    .unwrap_or_default(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Forwards input from one device until it is unplugged
+#[cfg(target_os = "linux")]
+fn read_device(
+    mut device: evdev::Device,
+    device_id: u32,
+    kind: DeviceKind,
+    sender: &Sender<EvdevEvent>,
+    forwarder: Option<&Forwarder>,
+) {
+    let name = device.name().unwrap_or("unnamed device").to_string();
+    let axis_ranges: Vec<(i32, i32)> = match device.get_abs_state() {
+        Ok(state) => GAMEPAD_AXES
+            .iter()
+            .map(|(axis, _)| {
+                let info = state[axis.0 as usize];
+                (info.minimum, info.maximum)
+            })
+            .collect(),
+        Err(_) => vec![(0, 0); GAMEPAD_AXES.len()],
+    };
+    // Pads with analog triggers often report BTN_TL2/BTN_TR2 as well. Those triggers are
+    // read from their axis only, so the thresholds decide when they count as held.
+    let analog_triggers: Vec<Input> = match device.supported_absolute_axes() {
+        Some(axes) => TRIGGER_AXES
+            .iter()
+            .filter(|(axis, _)| axes.contains(*axis))
+            .map(|(_, input)| *input)
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut pressed: Vec<Input> = Vec::new();
+    let mut axis_values: Vec<(gamepad::Axis, f32)> = Vec::new();
+
+    if kind == DeviceKind::Gamepad {
+        let id = device.input_id();
+        let info = ControllerInfo {
+            name: name.clone(),
+            guid: Some(format!(
+                "{:04x}{:04x}{:04x}{:04x}",
+                id.bus_type().0,
+                id.vendor(),
+                id.product(),
+                id.version()
+            )),
+            serial: device
+                .unique_name()
+                .filter(|serial| !serial.is_empty())
+                .map(str::to_string),
+        };
+        let _ = sender.send(EvdevEvent {
+            device_id,
+            change: EvdevChange::Connected { info },
+            timestamp: SystemTime::now(),
+        });
+
+        if forwarder.is_some() {
+            match device.grab() {
+                Ok(()) => info!("Grabbed {}, the game now only sees it through HKCM", name),
+                Err(err) => warn!(
+                    "Failed to grab {}, the game will see it twice: {}",
+                    name, err
+                ),
+            }
+        }
+    }
+
+    loop {
+        let events = match device.fetch_events() {
+            Ok(events) => events,
+            Err(err) => {
+                info!("Stopped reading input from {}: {}", name, err);
+                break;
+            }
+        };
+
+        for event in events {
+            for change in decode_event(&event, kind, &axis_ranges) {
+                // Hats report both directions on every movement, only pass on actual changes
+                match change {
+                    EvdevChange::Button {
+                        input,
+                        pressed: is_pressed,
+                    } => {
+                        if analog_triggers.contains(&input)
+                            || pressed.contains(&input) == is_pressed
+                        {
+                            continue;
+                        }
+                        if is_pressed {
+                            pressed.push(input);
+                        } else {
+                            pressed.retain(|held| *held != input);
+                        }
+                    }
+                    EvdevChange::Axis { axis, value } => {
+                        axis_values.retain(|(moved, _)| *moved != axis);
+                        axis_values.push((axis, value));
+                    }
+                    EvdevChange::Connected { .. } | EvdevChange::Disconnected => {}
+                }
+
+                if let Some(forward) = forwarder {
+                    forward(device_id, &change);
+                }
+                let event = EvdevEvent {
+                    device_id,
+                    change,
+                    timestamp: event.timestamp(),
+                };
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Don't leave inputs from an unplugged device stuck down
+    let released = pressed
+        .into_iter()
+        .map(|input| EvdevChange::Button {
+            input,
+            pressed: false,
+        })
+        .chain(
+            axis_values
+                .into_iter()
+                .map(|(axis, _)| EvdevChange::Axis { axis, value: 0.0 }),
+        )
+        .chain((kind == DeviceKind::Gamepad).then_some(EvdevChange::Disconnected));
+    for change in released {
+        if let Some(forward) = forwarder {
+            forward(device_id, &change);
+        }
+        let _ = sender.send(EvdevEvent {
+            device_id,
+            change,
+            timestamp: SystemTime::now(),
+        });
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use evdev::{EventType, InputEvent};
+
+    /// Ranges for `GAMEPAD_AXES`, sticks centred on 128 and 10 bit triggers
+    const AXIS_RANGES: &[(i32, i32)] =
+        &[(0, 255), (0, 255), (0, 255), (0, 255), (0, 1023), (0, 1023)];
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.code(), value)
+    }
+
+    fn abs(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+        InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+    }
+
+    fn button(input: Input, pressed: bool) -> EvdevChange {
+        EvdevChange::Button { input, pressed }
+    }
+
+    #[test]
+    fn normalize_axis_scales_sticks_and_triggers() {
+        let stick = gamepad::Axis::LeftX;
+        assert_eq!(normalize_axis(stick, 0, (0, 255)), -1.0);
+        assert_eq!(normalize_axis(stick, 255, (0, 255)), 1.0);
+        assert_eq!(normalize_axis(stick, 0, (-32768, 32768)), 0.0);
+
+        let trigger = gamepad::Axis::TriggerRight;
+        assert_eq!(normalize_axis(trigger, 0, (0, 1023)), 0.0);
+        assert_eq!(normalize_axis(trigger, 1023, (0, 1023)), 1.0);
+
+        // Devices that report no range can't be scaled
+        assert_eq!(normalize_axis(stick, 12, (0, 0)), 0.0);
+    }
+
+    #[test]
+    fn gamepad_buttons_decode_and_repeats_are_dropped() {
+        let south = Input::Button(gamepad::Button::South);
+        let decode = |event| decode_event(&event, DeviceKind::Gamepad, AXIS_RANGES);
+
+        assert_eq!(decode(key(Key::BTN_SOUTH, 1)), [button(south, true)]);
+        assert_eq!(decode(key(Key::BTN_SOUTH, 0)), [button(south, false)]);
+        assert_eq!(decode(key(Key::BTN_SOUTH, KEY_REPEAT)), []);
+        // Keyboard keys on a gamepad aren't one of its buttons
+        assert_eq!(decode(key(Key::KEY_Z, 1)), []);
+    }
+
+    #[test]
+    fn hats_decode_to_both_d_pad_directions() {
+        let left = Input::Button(gamepad::Button::DPadLeft);
+        let right = Input::Button(gamepad::Button::DPadRight);
+        let decode = |event| decode_event(&event, DeviceKind::Gamepad, AXIS_RANGES);
+
+        assert_eq!(
+            decode(abs(AbsoluteAxisType::ABS_HAT0X, -1)),
+            [button(left, true), button(right, false)]
+        );
+        assert_eq!(
+            decode(abs(AbsoluteAxisType::ABS_HAT0X, 0)),
+            [button(left, false), button(right, false)]
+        );
+    }
+
+    #[test]
+    fn axes_decode_with_their_device_range() {
+        let decode = |event| decode_event(&event, DeviceKind::Gamepad, AXIS_RANGES);
+
+        assert_eq!(
+            decode(abs(AbsoluteAxisType::ABS_X, 255)),
+            [EvdevChange::Axis {
+                axis: gamepad::Axis::LeftX,
+                value: 1.0,
+            }]
+        );
+        assert_eq!(
+            decode(abs(AbsoluteAxisType::ABS_Z, 1023)),
+            [EvdevChange::Axis {
+                axis: gamepad::Axis::TriggerLeft,
+                value: 1.0,
+            }]
+        );
+        // Keyboards have no axes
+        assert_eq!(
+            decode_event(
+                &abs(AbsoluteAxisType::ABS_X, 255),
+                DeviceKind::Keyboard,
+                AXIS_RANGES
+            ),
+            []
+        );
+    }
+
+    #[test]
+    fn keyboard_keys_decode_by_code() {
+        let z = Input::from_key_code(Key::KEY_Z.code()).unwrap();
+        let decode = |event| decode_event(&event, DeviceKind::Keyboard, AXIS_RANGES);
+
+        assert_eq!(decode(key(Key::KEY_Z, 1)), [button(z, true)]);
+        assert_eq!(decode(key(Key::KEY_Z, KEY_REPEAT)), []);
+    }
+}
@@ -0,0 +1,287 @@
+use asr::{
+    file_format::{elf, pe},
+    game_engine::unity::mono::{self, UnityPointer},
+    Address, PointerSize, Process,
+};
+use std::{thread::sleep, time::Duration};
+
+/// Longest string read from the game, anything longer is treated as unreadable
+const MAX_STRING_LENGTH: i32 = 256;
+
+/// The dialogue box keeps a "hidden" flag this many bytes in
+const DIALOGUE_HIDDEN_OFFSET: u32 = 0x2E;
+
+/// How many times to look for the mono runtime before giving up until the next attach
+const MONO_ATTACH_ATTEMPTS: u32 = 40;
+
+struct HKConfig {
+    module_name: &'static str,
+    base_offset: u32,
+    pointer_chain: &'static [u32],
+}
+
+static CONFIGS: &[(&str, HKConfig)] = &[
+    (
+        "hollow_knight.x86_64",
+        HKConfig {
+            module_name: "libmono.so",
+            base_offset: 0x004AAA68,
+            pointer_chain: &[0x138, 0x898, 0x20, 0x28, 0x10c],
+        },
+    ),
+    (
+        "Hollow Knight.exe",
+        HKConfig {
+            module_name: "Hollow Knight.exe",
+            base_offset: 0x00FB85AC,
+            pointer_chain: &[0x20, 0x4, 0x10, 0x4, 0x4, 0x50, 0x38, 0x0],
+        },
+    ),
+    (
+        "hollow_knight.exe",
+        HKConfig {
+            module_name: "hollow_knight.exe",
+            base_offset: 0x00FB85AC,
+            pointer_chain: &[0x20, 0x4, 0x10, 0x4, 0x4, 0x50, 0x38, 0x0],
+        },
+    ),
+];
+
+/// `GlobalEnums.GameState`, what `GameManager` is busy with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameState {
+    Inactive,
+    MainMenu,
+    Loading,
+    EnteringLevel,
+    Playing,
+    Paused,
+    ExitingLevel,
+    Cutscene,
+    Primer,
+}
+
+impl GameState {
+    fn from_raw(value: i32) -> Option<GameState> {
+        Some(match value {
+            0 => GameState::Inactive,
+            1 => GameState::MainMenu,
+            2 => GameState::Loading,
+            3 => GameState::EnteringLevel,
+            4 => GameState::Playing,
+            5 => GameState::Paused,
+            6 => GameState::ExitingLevel,
+            7 => GameState::Cutscene,
+            8 => GameState::Primer,
+            _ => return None,
+        })
+    }
+}
+
+/// What the masher reads from the game. `HollowKnightState` reads a running game,
+/// and tests stand in a fake one.
+pub trait GameReader {
+    /// The process name the game was found by, one of `CONFIGS`
+    fn build(&self) -> &'static str;
+
+    /// Whether the game is still running
+    fn is_running(&self) -> bool;
+
+    /// Whether a dialogue box is on screen, `None` when the dialogue box couldn't be found
+    fn dialogue_visible(&self) -> Option<bool>;
+
+    /// `GameManager.inputHandler.acceptingInput`, false when it can't be read
+    fn accepting_input(&self) -> bool;
+
+    /// `DialogueBox.currentConversation`, the key of the conversation the dialogue box
+    /// is showing or last showed, such as `ELDERBUG_INTRO_MAIN`
+    fn conversation(&self) -> Option<String>;
+
+    /// `GameManager.sceneName`, the name of the room the knight is in
+    fn scene_name(&self) -> Option<String>;
+
+    /// `GameManager.gameState`
+    // Not read by the masher yet, kept with the other accessors for features that need it
+    #[allow(dead_code)]
+    fn game_state(&self) -> Option<GameState>;
+
+    /// Whether the pause menu is open
+    #[allow(dead_code)]
+    fn is_paused(&self) -> bool {
+        self.game_state() == Some(GameState::Paused)
+    }
+}
+
+/// A running Hollow Knight, read through its mono runtime.
+///
+/// The module, image and every `UnityPointer` are looked up once and kept, so repeated
+/// reads only follow pointers the game has already been searched for.
+pub struct HollowKnightState {
+    process: Process,
+    build: &'static str,
+    config: &'static HKConfig,
+    module_address: Address,
+    /// Pointer size of the game's build, 32-bit Windows builds still exist
+    pointer_size: PointerSize,
+    module: mono::Module,
+    image: mono::Image,
+    accepting_input: UnityPointer<3>,
+    scene_name: UnityPointer<2>,
+    game_state: UnityPointer<2>,
+    conversation: UnityPointer<2>,
+}
+
+impl HollowKnightState {
+    /// Finds a running Hollow Knight and waits for its mono runtime to load.
+    /// Returns `None` when the game isn't running or can't be read.
+    pub fn attach() -> Option<HollowKnightState> {
+        let (process, build, config) = CONFIGS.iter().find_map(|(name, config)| {
+            Process::attach(name).map(|process| (process, *name, config))
+        })?;
+        log::info!("Found Hollow Knight: {:?}", build);
+
+        let Ok(module_address) = process.get_module_address(config.module_name) else {
+            log::info!("Cannot attach to base module address");
+            return None;
+        };
+
+        let Some(pointer_size) = pe::MachineType::read(&process, module_address)
+            .and_then(|machine| machine.pointer_size())
+            .or_else(|| elf::pointer_size(&process, module_address))
+        else {
+            log::info!("Cannot read the pointer size of {}", config.module_name);
+            return None;
+        };
+
+        let mut attempts = 0;
+        let module = loop {
+            if let Some(module) = mono::Module::attach_auto_detect(&process) {
+                break module;
+            }
+            attempts += 1;
+            // Leave it to the next attach when the game closes or mono never shows up
+            if attempts == MONO_ATTACH_ATTEMPTS || process.read::<u8>(module_address).is_err() {
+                log::info!("GameManagerFinder wait_attach: gave up waiting for attach");
+                return None;
+            }
+            log::info!("GameManagerFinder wait_attach: waiting for attach...");
+            sleep(Duration::from_millis(250));
+        };
+        log::info!("GameManagerFinder wait_attach: module get_default_image...");
+        let image = (0..0x10).find_map(|_| module.get_default_image(&process))?;
+        log::info!("GameManagerFinder wait_attach: got module and image");
+
+        Some(HollowKnightState {
+            process,
+            build,
+            config,
+            module_address,
+            pointer_size,
+            module,
+            image,
+            accepting_input: UnityPointer::new(
+                "GameManager",
+                0,
+                &[
+                    "_instance",
+                    "<inputHandler>k__BackingField",
+                    "acceptingInput",
+                ],
+            ),
+            scene_name: UnityPointer::new("GameManager", 0, &["_instance", "sceneName"]),
+            game_state: UnityPointer::new("GameManager", 0, &["_instance", "gameState"]),
+            conversation: UnityPointer::new(
+                "DialogueBox",
+                0,
+                &["_instance", "currentConversation"],
+            ),
+        })
+    }
+
+    /// Follows `HKConfig::pointer_chain` to the dialogue box
+    fn dialogue_box(&self) -> Option<Address> {
+        let chain = self.config.pointer_chain;
+        let base = self.module_address + self.config.base_offset;
+        if chain.is_empty() {
+            return Some(base);
+        }
+
+        let ps = self.pointer_size;
+        let mut addr = self.process.read_pointer(base, ps).ok()?;
+        for &offset in &chain[..chain.len() - 1] {
+            addr = self.process.read_pointer(addr + offset, ps).ok()?;
+        }
+
+        Some(addr + chain[chain.len() - 1])
+    }
+
+    /// Follows `pointer` to a field that holds a reference, such as a string
+    fn deref_reference<const N: usize>(&self, pointer: &UnityPointer<N>) -> Option<Address> {
+        let value = match self.pointer_size {
+            PointerSize::Bit64 => pointer.deref::<u64>(&self.process, &self.module, &self.image),
+            _ => pointer
+                .deref::<u32>(&self.process, &self.module, &self.image)
+                .map(u64::from),
+        };
+        Some(Address::new(value.ok()?))
+    }
+
+    fn read_string(&self, string_addr: Address) -> Option<String> {
+        if string_addr.is_null() {
+            return None;
+        }
+
+        // A mono string is its object header of two pointers, an i32 length and then
+        // the UTF-16 characters
+        let length_offset = 2 * self.pointer_size as u64;
+        let length = self.process.read::<i32>(string_addr + length_offset).ok()?;
+        if !(0..=MAX_STRING_LENGTH).contains(&length) {
+            return None;
+        }
+        let mut chars = vec![0u16; length as usize];
+        self.process
+            .read_into_slice(string_addr + (length_offset + 4), &mut chars)
+            .ok()?;
+        Some(String::from_utf16_lossy(&chars))
+    }
+}
+
+impl GameReader for HollowKnightState {
+    fn build(&self) -> &'static str {
+        self.build
+    }
+
+    fn is_running(&self) -> bool {
+        self.process.read::<u8>(self.module_address).is_ok()
+    }
+
+    fn dialogue_visible(&self) -> Option<bool> {
+        let dialogue_box = self.dialogue_box()?;
+        Some(matches!(
+            self.process
+                .read::<u8>(dialogue_box + DIALOGUE_HIDDEN_OFFSET),
+            Ok(0)
+        ))
+    }
+
+    fn accepting_input(&self) -> bool {
+        self.accepting_input
+            .deref(&self.process, &self.module, &self.image)
+            .unwrap_or_default()
+    }
+
+    fn conversation(&self) -> Option<String> {
+        self.read_string(self.deref_reference(&self.conversation)?)
+    }
+
+    fn scene_name(&self) -> Option<String> {
+        self.read_string(self.deref_reference(&self.scene_name)?)
+    }
+
+    fn game_state(&self) -> Option<GameState> {
+        self.game_state
+            .deref::<i32>(&self.process, &self.module, &self.image)
+            .ok()
+            .and_then(GameState::from_raw)
+    }
+}
@@ -1,13 +1,33 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod cli;
+mod evdev_input;
+mod hollow_knight;
 mod livesplit_core;
+mod proxy;
+mod settings;
 mod text_masher;
-
+mod virtual_pad;
+
+use crate::cli::{Cli, Command};
+use crate::evdev_input::{EvdevChange, EvdevReader, KEYBOARD_ID};
+use crate::hollow_knight::HollowKnightState;
+use crate::proxy::Proxy;
+use crate::settings::{
+    load_settings, read_settings, save_settings, watch_settings, AxisThresholds, ControllerInfo,
+    ControllerMerge, Input, Profile, Settings, MAX_CHORD_LENGTH,
+};
 use crate::text_masher::{
-    text_masher, IS_MASHER_ACTIVE, MAX_MASHING_KEY_COUNT, SHOULD_TERMINATE_MASHER,
+    resolve_mash_sequence, Activation, ActivationMode, Masher, MasherCommand, MasherConfig,
+    MasherEvent, MasherHandle, MAX_AUTO_START_DELAY_MS, MAX_BUTTON_GAP_MS, MAX_MASH_RATE,
+    MAX_TOGGLE_TIMEOUT_SECS, MIN_DUTY_CYCLE, MIN_MASH_RATE,
+};
+use crate::virtual_pad::{
+    is_vigem_pad, is_virtual_device, platform_pad, proxy_pad, PadSource, SharedPad,
 };
+use clap::Parser;
 use log::LevelFilter;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::rolling_file::policy::compound::roll::delete::DeleteRoller;
 use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
@@ -18,25 +38,19 @@ use sdl3::event::Event;
 use sdl3::gamepad;
 use sdl3::pixels::Color;
 use sdl3::rect::Rect;
-use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::Write;
-use std::sync::atomic::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 #[cfg(target_os = "linux")]
-use {
-    std::os::unix::net::UnixStream, uinput::event::absolute::Hat,
-    uinput::event::absolute::Position, uinput::event::controller::GamePad, uinput::event::Code,
-    uinput::event::Controller, uinput::Event::Absolute,
-};
+use {std::io::Write, std::os::unix::net::UnixStream};
 
 #[cfg(target_os = "windows")]
 use {
     std::os::windows::ffi::OsStrExt,
-    vigem_client::XButtons,
     windows::core::PCWSTR,
     windows::Win32::Foundation::CloseHandle,
     windows::Win32::Storage::FileSystem::{
@@ -46,134 +60,734 @@ use {
 };
 
 enum AppState {
-    DetectConfig,
+    /// Capturing a new trigger chord of `chord_length` inputs. `already_held` are the inputs
+    /// that were held when the capture started, such as the mouse button that clicked
+    /// 'Configure'. They only count once they have been released and pressed again.
+    DetectConfig {
+        already_held: HashMap<u32, Vec<Input>>,
+        chord_length: usize,
+    },
     AcceptingInput,
 }
 
-#[cfg(target_os = "windows")]
-#[derive(Serialize, Deserialize)]
-struct Settings {
-    mashing_triggers: Vec<VigemInput>,
+fn press_held_input(held_buttons: &mut HashMap<u32, Vec<Input>>, which: u32, input: Input) {
+    let held = held_buttons.entry(which).or_default();
+    if !held.iter().any(|x| *x == input) {
+        held.push(input);
+    }
 }
 
-#[cfg(target_os = "linux")]
-struct Settings {
-    mashing_triggers: Vec<Controller>,
+fn release_held_input(held_buttons: &mut HashMap<u32, Vec<Input>>, which: u32, input: Input) {
+    if let Some(entry) = held_buttons.get_mut(&which) {
+        entry.retain(|held| *held != input);
+
+        if entry.is_empty() {
+            held_buttons.remove_entry(&which);
+        }
+    }
 }
 
-#[cfg(target_os = "linux")]
-impl Serialize for Settings {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        // Convert each Controller to its code
-        let codes: Vec<i32> = self
-            .mashing_triggers
-            .iter()
-            .map(|ctrl| ctrl.code())
-            .collect();
-
-        let mut state = serializer.serialize_struct("Settings", 1)?;
-        state.serialize_field("mashing_triggers", &codes)?;
-        state.end()
+/// How many connected controllers the window has room to list
+const MAX_LISTED_CONTROLLERS: usize = 4;
+
+/// Connected controllers in a stable order for listing
+fn sorted_controllers(controllers: &HashMap<u32, ControllerInfo>) -> Vec<(u32, &ControllerInfo)> {
+    let mut sorted: Vec<(u32, &ControllerInfo)> = controllers
+        .iter()
+        .map(|(which, info)| (*which, info))
+        .collect();
+    sorted.sort_by_key(|(which, _)| *which);
+    sorted
+}
+
+/// Id of the combined entry `chord_held_inputs` makes for merged controllers
+const MERGED_ID: u32 = u32::MAX - 1;
+
+/// The held inputs that count towards chords. Controllers that aren't allowed to trigger
+/// HKCM are left out, and the ones picked by `merge_controllers` are pooled into one entry
+/// so a chord can be spread across them.
+fn chord_held_inputs(
+    held_buttons: &HashMap<u32, Vec<Input>>,
+    settings: &Settings,
+    controllers: &HashMap<u32, ControllerInfo>,
+) -> HashMap<u32, Vec<Input>> {
+    // the keyboard has no entry in `controllers` and is always allowed
+    let is_allowed = |which: &u32| {
+        controllers
+            .get(which)
+            .is_none_or(|info| settings.is_controller_allowed(info))
+    };
+    let is_merged = |which: &u32| match &settings.merge_controllers {
+        ControllerMerge::None => false,
+        ControllerMerge::All => true,
+        ControllerMerge::Selected(names) => controllers
+            .get(which)
+            .is_some_and(|info| names.contains(&info.name)),
+    };
+
+    let mut merged = HashMap::new();
+    for (which, held) in held_buttons.iter().filter(|(which, _)| is_allowed(which)) {
+        if is_merged(which) {
+            for input in held {
+                press_held_input(&mut merged, MERGED_ID, *input);
+            }
+        } else {
+            merged.insert(*which, held.clone());
+        }
     }
+    merged
 }
 
-#[cfg(target_os = "linux")]
-impl<'de> Deserialize<'de> for Settings {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        struct Helper {
-            mashing_triggers: Vec<i32>,
+/// Presses and releases the inputs on both ends of `axis` for its new position.
+/// Returns true if anything changed.
+fn update_axis_inputs(
+    held_buttons: &mut HashMap<u32, Vec<Input>>,
+    which: u32,
+    axis: gamepad::Axis,
+    value: f32,
+    thresholds: &AxisThresholds,
+) -> bool {
+    let mut changed = false;
+    for positive in [false, true] {
+        let Some(input) = Input::from_axis(axis, positive) else {
+            continue;
+        };
+        let Some(threshold) = thresholds.for_input(input) else {
+            continue;
+        };
+
+        let travel = if positive { value } else { -value };
+        let was_held = held_buttons
+            .get(&which)
+            .is_some_and(|held| held.contains(&input));
+        let is_held = threshold.is_held(travel, was_held);
+        if is_held == was_held {
+            continue;
+        }
+
+        debug!(
+            "controller {} {} at {:.2}",
+            if is_held { "down" } else { "up" },
+            input.name(),
+            travel
+        );
+        if is_held {
+            press_held_input(held_buttons, which, input);
+        } else {
+            release_held_input(held_buttons, which, input);
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// Tracks which inputs each connected gamepad is holding.
+/// HKCM's own virtual pad is never opened, so none of its inputs are seen.
+/// Returns true if the event was a gamepad input or connection that should refresh the window.
+fn handle_gamepad_event(
+    event: &Event,
+    gamepad_system: &sdl3::GamepadSubsystem,
+    opened_gamepads: &mut HashMap<u32, sdl3::gamepad::Gamepad>,
+    held_buttons: &mut HashMap<u32, Vec<Input>>,
+    controllers: &mut HashMap<u32, ControllerInfo>,
+    thresholds: &AxisThresholds,
+) -> bool {
+    match *event {
+        Event::ControllerDeviceAdded { which, .. } => {
+            let Ok(gamepad) = gamepad_system.open(which) else {
+                return false;
+            };
+
+            let name = gamepad.name();
+            if is_virtual_device(name.as_deref(), gamepad.vendor_id(), gamepad.product_id())
+                || is_vigem_pad(gamepad.player_index())
+            {
+                // dropping the gamepad closes it again, so SDL sends no events for it
+                info!("Ignoring HKCM's own virtual controller");
+                return false;
+            }
+            let info = ControllerInfo {
+                name: name.unwrap_or_else(|| "unnamed controller".to_string()),
+                guid: Some(gamepad_system.guid_for_id(which).string()),
+                serial: gamepad.serial_number().filter(|serial| !serial.is_empty()),
+            };
+            info!("Controller connected: {:?}", info);
+            controllers.insert(which, info);
+            opened_gamepads.insert(which, gamepad);
+            true
+        }
+        Event::ControllerDeviceRemoved { which, .. } => {
+            opened_gamepads.remove(&which);
+            controllers.remove(&which);
+            held_buttons.remove(&which);
+            true
+        }
+        Event::ControllerButtonDown { which, button, .. } => {
+            debug!("controller down {}", button.string());
+
+            if let Some(input) = Input::from_button(button) {
+                press_held_input(held_buttons, which, input);
+            }
+            true
         }
+        Event::ControllerButtonUp { which, button, .. } => {
+            debug!("controller up {}", button.string());
 
-        let helper = Helper::deserialize(deserializer)?;
+            if let Some(input) = Input::from_button(button) {
+                release_held_input(held_buttons, which, input);
+            }
+            true
+        }
+        Event::ControllerAxisMotion {
+            which, axis, value, ..
+        } => {
+            let value = value as f32 / i16::MAX as f32;
+            update_axis_inputs(held_buttons, which, axis, value, thresholds)
+        }
+        _ => false,
+    }
+}
 
-        // Convert codes back into Controller objects
-        let controllers: Vec<Controller> = helper
-            .mashing_triggers
-            .into_iter()
-            .map(code_to_controller)
-            .collect();
+/// Turns the masher on and off from the trigger chord, following the profile's activation mode
+struct MasherActivation {
+    /// Whether every trigger input was held at the last update, either on one controller
+    /// or spread across the controllers pooled by `merge_controllers`
+    chord_held: bool,
+    /// Whether the masher was last told to activate
+    active: bool,
+    /// When toggle mode last armed the masher
+    armed_at: Option<Instant>,
+    events: Receiver<MasherEvent>,
+}
+
+impl MasherActivation {
+    fn new(masher: &MasherHandle) -> Self {
+        MasherActivation {
+            chord_held: false,
+            active: false,
+            armed_at: None,
+            events: masher.subscribe(),
+        }
+    }
+
+    fn set_active(&mut self, active: bool, masher_state: &MasherState) {
+        if active != self.active {
+            self.active = active;
+            masher_state.masher.send(if active {
+                MasherCommand::Activate
+            } else {
+                MasherCommand::Deactivate
+            });
+        }
+    }
+
+    /// Reacts to a change in held inputs. In hold mode the masher is active while the chord
+    /// is held, in toggle mode each new press of the chord arms or disarms it, and in auto
+    /// mode the chord is ignored.
+    ///
+    /// `held_buttons` comes from `chord_held_inputs`, so only controllers allowed to trigger
+    /// HKCM are in it. HKCM's virtual pad is filtered out when it connects, so the
+    /// masher's own presses can never complete a chord.
+    fn update(&mut self, held_buttons: &HashMap<u32, Vec<Input>>, masher_state: &MasherState) {
+        let mut chord_held = false;
+        let triggers = masher_state.mashing_buttons.read().unwrap().clone();
+        if !triggers.is_empty() {
+            for (_, val) in held_buttons.iter() {
+                // merged controllers share a single entry, so this covers chords spread across them
+                chord_held = triggers.iter().all(|button| val.contains(button));
+                if chord_held {
+                    break;
+                };
+            }
+        }
+
+        let chord_pressed = chord_held && !self.chord_held;
+        if chord_held != self.chord_held {
+            debug!("all mashing triggers pressed: {}", chord_held);
+            self.chord_held = chord_held;
+        }
+
+        let mode = masher_state.activation.read().unwrap().mode;
+        match mode {
+            ActivationMode::Hold => {
+                self.armed_at = None;
+                self.set_active(chord_held, masher_state);
+            }
+            ActivationMode::Toggle if chord_pressed => {
+                if self.active {
+                    info!("Trigger chord pressed again, disarming the masher");
+                    self.set_active(false, masher_state);
+                    self.armed_at = None;
+                } else {
+                    info!("Masher armed until the next dialogue is mashed through");
+                    self.set_active(true, masher_state);
+                    self.armed_at = Some(Instant::now());
+                }
+            }
+            ActivationMode::Toggle => {}
+            // the masher finds dialogues by itself, the chord does nothing
+            ActivationMode::Auto => {
+                self.armed_at = None;
+                self.set_active(false, masher_state);
+            }
+        }
+    }
+
+    /// Called on every pass of the main loop. Notices the masher disarming itself after a
+    /// dialogue, and disarms toggle mode once its timeout runs out. If the profile left toggle
+    /// mode while armed, the masher goes back to following the chord.
+    fn tick(&mut self, masher_state: &MasherState) {
+        for event in self.events.try_iter() {
+            if event == MasherEvent::Deactivated {
+                // the masher disarmed itself after mashing through a dialogue
+                self.active = false;
+                self.armed_at = None;
+            }
+        }
+
+        let Some(armed_at) = self.armed_at else {
+            return;
+        };
+        let activation = masher_state.activation.read().unwrap().clone();
+
+        if activation.mode != ActivationMode::Toggle {
+            // the profile changed modes while armed
+            self.armed_at = None;
+            self.set_active(self.chord_held, masher_state);
+        } else if activation.toggle_timeout_secs > 0
+            && armed_at.elapsed() >= Duration::from_secs(activation.toggle_timeout_secs)
+        {
+            info!(
+                "Disarming the masher after {} seconds",
+                activation.toggle_timeout_secs
+            );
+            self.set_active(false, masher_state);
+            self.armed_at = None;
+        }
+    }
+}
+
+/// The masher's settings from a profile
+fn masher_config(profile: &Profile) -> MasherConfig {
+    MasherConfig {
+        activation: profile.activation.clone(),
+        mash_timing: profile.mash_timing,
+        mash_sequence: resolve_mash_sequence(&profile.mash_sequence, &profile.mashing_triggers),
+    }
+}
+
+/// The running masher, along with the parts of the active profile
+/// the main loop and the proxy read
+#[derive(Clone)]
+struct MasherState {
+    masher: MasherHandle,
+    mashing_buttons: Arc<RwLock<Vec<Input>>>,
+    activation: Arc<RwLock<Activation>>,
+}
+
+impl MasherState {
+    fn new(profile: &Profile, masher: MasherHandle) -> Self {
+        MasherState {
+            masher,
+            mashing_buttons: Arc::new(RwLock::new(profile.mashing_triggers.clone())),
+            activation: Arc::new(RwLock::new(profile.activation.clone())),
+        }
+    }
+
+    /// Points the masher at `profile`, taking effect from its next step
+    fn apply(&self, profile: &Profile) {
+        *self
+            .mashing_buttons
+            .write()
+            .expect("Failed to get state while storing config") = profile.mashing_triggers.clone();
+        *self
+            .activation
+            .write()
+            .expect("Failed to get state while storing activation") = profile.activation.clone();
+        self.masher
+            .send(MasherCommand::ReloadConfig(masher_config(profile)));
+    }
+}
 
-        Ok(Settings {
-            mashing_triggers: controllers,
+/// Switches to the profile whose switch chord a single controller is holding.
+/// When several match, the one with the longest chord wins.
+/// Returns true if the active profile changed.
+fn switch_profile_from_chord(
+    held_buttons: &HashMap<u32, Vec<Input>>,
+    settings: &mut Settings,
+    settings_path: &Path,
+    masher_state: &MasherState,
+) -> bool {
+    let chosen = settings
+        .profiles
+        .iter()
+        .filter(|profile| !profile.switch_chord.is_empty())
+        .filter(|profile| {
+            held_buttons.values().any(|held| {
+                profile
+                    .switch_chord
+                    .iter()
+                    .all(|button| held.contains(button))
+            })
         })
+        .max_by_key(|profile| profile.switch_chord.len())
+        .map(|profile| profile.name.clone());
+
+    let Some(name) = chosen else {
+        return false;
+    };
+    if name == settings.active_profile {
+        return false;
     }
+
+    settings.select_profile(&name);
+    masher_state.apply(settings.active());
+    save_settings(settings, settings_path);
+    info!("Switched to profile {:?}", name);
+    true
 }
 
-#[cfg(target_os = "linux")]
-fn code_to_controller(code: i32) -> Controller {
-    use uinput::event::controller::*;
-    match code {
-        0x133 => Controller::GamePad(GamePad::North),
-        0x131 => Controller::GamePad(GamePad::East),
-        0x130 => Controller::GamePad(GamePad::South),
-        0x134 => Controller::GamePad(GamePad::West),
-        0x13A => Controller::GamePad(GamePad::Select),
-        0x13C => Controller::GamePad(GamePad::Mode),
-        0x13B => Controller::GamePad(GamePad::Start),
-        0x136 => Controller::GamePad(GamePad::TL),
-        0x137 => Controller::GamePad(GamePad::TR),
-        _ => uinput::event::controller::Controller::All,
+/// Moves presses read through evdev into `held_buttons`. Returns true if anything changed.
+fn handle_evdev_events(
+    reader: &mut EvdevReader,
+    enabled: bool,
+    held_buttons: &mut HashMap<u32, Vec<Input>>,
+    controllers: &mut HashMap<u32, ControllerInfo>,
+    thresholds: &AxisThresholds,
+) -> bool {
+    let mut changed = false;
+    for event in reader.poll(enabled) {
+        match event.change {
+            EvdevChange::Button { input, pressed } => {
+                let latency = SystemTime::now()
+                    .duration_since(event.timestamp)
+                    .unwrap_or_default();
+                debug!(
+                    "evdev {} {} on device {:#x}, {:?} ago",
+                    input.name(),
+                    if pressed { "down" } else { "up" },
+                    event.device_id,
+                    latency
+                );
+
+                if pressed {
+                    press_held_input(held_buttons, event.device_id, input);
+                } else {
+                    release_held_input(held_buttons, event.device_id, input);
+                }
+                changed = true;
+            }
+            EvdevChange::Axis { axis, value } => {
+                changed |=
+                    update_axis_inputs(held_buttons, event.device_id, axis, value, thresholds);
+            }
+            EvdevChange::Connected { info } => {
+                info!("Controller connected: {:?}", info);
+                controllers.insert(event.device_id, info);
+                changed = true;
+            }
+            EvdevChange::Disconnected => {
+                controllers.remove(&event.device_id);
+                changed = true;
+            }
+        }
     }
+    changed
 }
 
-#[cfg(target_os = "windows")]
-#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, Hash, Eq)]
-enum VigemInput {
-    Button(u16),
-    LeftTrigger,
-    RightTrigger,
+/// Moves keyboard and mouse presses into `held_buttons`, as if they came from one more controller.
+/// Returns true if anything changed.
+fn handle_keyboard_events(
+    keyboard_reader: &mut EvdevReader,
+    enabled: bool,
+    held_buttons: &mut HashMap<u32, Vec<Input>>,
+    controllers: &mut HashMap<u32, ControllerInfo>,
+    thresholds: &AxisThresholds,
+) -> bool {
+    let changed = handle_evdev_events(
+        keyboard_reader,
+        enabled,
+        held_buttons,
+        controllers,
+        thresholds,
+    );
+    if !enabled {
+        return held_buttons.remove(&KEYBOARD_ID).is_some();
+    }
+    changed
 }
 
-// #[cfg(target_os = "linux")]
-// #[derive(Debug, Clone, Copy)]
-// enum UInputOutput {
-//     Key(uinput::event::keyboard::Key),
-//     KeyPad(uinput::event::keyboard::KeyPad),
-//     GamePad(GamePad),
-// }
+/// Reacts to a change in held inputs. While accepting input this switches profiles and
+/// (de)activates the masher, while configuring it captures the new trigger chord.
+/// Returns true if the window needs redrawing.
+fn process_held_inputs(
+    app_state: &mut AppState,
+    held_buttons: &HashMap<u32, Vec<Input>>,
+    controllers: &HashMap<u32, ControllerInfo>,
+    settings: &mut Settings,
+    settings_path: &Path,
+    masher_state: &MasherState,
+    activation: &mut MasherActivation,
+) -> bool {
+    let held_buttons = &chord_held_inputs(held_buttons, settings, controllers);
+    match app_state {
+        AppState::AcceptingInput => {
+            let switched =
+                switch_profile_from_chord(held_buttons, settings, settings_path, masher_state);
+            activation.update(held_buttons, masher_state);
+            switched
+        }
+        AppState::DetectConfig {
+            already_held,
+            chord_length,
+        } => {
+            for (which, held) in already_held.iter_mut() {
+                let still_held = held_buttons.get(which);
+                held.retain(|input| still_held.is_some_and(|still| still.contains(input)));
+            }
+            already_held.retain(|_, held| !held.is_empty());
+
+            let chord_length = *chord_length;
+            let Some((which, chord)) = held_buttons
+                .iter()
+                .map(|(which, held)| {
+                    let old = already_held.get(which);
+                    let pressed = held
+                        .iter()
+                        .filter(|input| !old.is_some_and(|old| old.contains(input)))
+                        .copied()
+                        .collect::<Vec<Input>>();
+                    (*which, pressed)
+                })
+                .find(|(_, pressed)| pressed.len() == chord_length)
+            else {
+                return false;
+            };
+
+            let profile = settings.active_mut();
+            let previous_triggers = std::mem::replace(&mut profile.mashing_triggers, chord.clone());
+            let previous_length = std::mem::replace(&mut profile.chord_length, chord.len());
+            if let Err(err) = settings.validate() {
+                warn!("Can't use that chord as triggers, {}", err);
+                let profile = settings.active_mut();
+                profile.chord_length = previous_length;
+                profile.mashing_triggers = previous_triggers;
+                // Wait for it to be released instead of rejecting it on every event
+                already_held.entry(which).or_default().extend(chord);
+                return false;
+            }
+
+            *app_state = AppState::AcceptingInput;
+            masher_state.apply(settings.active());
 
-#[cfg(target_os = "windows")]
-fn sdl_button_to_input(button: gamepad::Button) -> Option<VigemInput> {
-    match button {
-        gamepad::Button::North => Some(VigemInput::Button(XButtons::Y)),
-        gamepad::Button::East => Some(VigemInput::Button(XButtons::B)),
-        gamepad::Button::South => Some(VigemInput::Button(XButtons::A)),
-        gamepad::Button::West => Some(VigemInput::Button(XButtons::X)),
-        gamepad::Button::Back => Some(VigemInput::Button(XButtons::BACK)),
-        gamepad::Button::Guide => Some(VigemInput::Button(XButtons::GUIDE)),
-        gamepad::Button::Start => Some(VigemInput::Button(XButtons::START)),
-        gamepad::Button::LeftStick => Some(VigemInput::Button(XButtons::LTHUMB)),
-        gamepad::Button::RightStick => Some(VigemInput::Button(XButtons::RTHUMB)),
-        gamepad::Button::LeftShoulder => Some(VigemInput::Button(XButtons::LB)),
-        gamepad::Button::RightShoulder => Some(VigemInput::Button(XButtons::RB)),
-        gamepad::Button::DPadUp => Some(VigemInput::Button(XButtons::UP)),
-        gamepad::Button::DPadDown => Some(VigemInput::Button(XButtons::DOWN)),
-        gamepad::Button::DPadLeft => Some(VigemInput::Button(XButtons::LEFT)),
-        gamepad::Button::DPadRight => Some(VigemInput::Button(XButtons::RIGHT)),
-        _ => None, // not supported in vigem
+            save_settings(settings, settings_path);
+            info!("Config set, now accepting input");
+            true
+        }
     }
 }
 
-#[cfg(target_os = "linux")]
-fn sdl_button_to_input(button: gamepad::Button) -> Option<Controller> {
-    match button {
-        gamepad::Button::North => Some(Controller::GamePad(GamePad::North)),
-        gamepad::Button::East => Some(Controller::GamePad(GamePad::East)),
-        gamepad::Button::South => Some(Controller::GamePad(GamePad::South)),
-        gamepad::Button::West => Some(Controller::GamePad(GamePad::West)),
-        gamepad::Button::Back => Some(Controller::GamePad(GamePad::Select)),
-        gamepad::Button::Guide => Some(Controller::GamePad(GamePad::Mode)),
-        gamepad::Button::Start => Some(Controller::GamePad(GamePad::Start)),
-        gamepad::Button::LeftShoulder => Some(Controller::GamePad(GamePad::TL)),
-        gamepad::Button::RightShoulder => Some(Controller::GamePad(GamePad::TR)),
-        _ => None,
+/// Applies any settings file edits picked up by `watch_settings`.
+/// Returns true if the settings changed.
+fn apply_reloaded_settings(
+    settings_updates: &Receiver<Settings>,
+    settings: &mut Settings,
+    masher_state: &MasherState,
+    proxy: Option<&Proxy>,
+) -> bool {
+    let mut changed = false;
+    for reloaded in settings_updates.try_iter() {
+        *settings = reloaded;
+        masher_state.apply(settings.active());
+        if let Some(proxy) = proxy {
+            proxy.apply(settings);
+        }
+        info!(
+            "Reloaded settings from file, active profile is {:?}",
+            settings.active_profile
+        );
+        changed = true;
+    }
+    changed
+}
+
+/// Runs the trigger detection loop without creating a window, for running HKCM in the background.
+/// Settings are only read from the settings file.
+fn run_headless(
+    use_evdev: bool,
+    settings: &mut Settings,
+    settings_path: &Path,
+    settings_updates: &Receiver<Settings>,
+    masher_state: &MasherState,
+    proxy: Option<&Arc<Proxy>>,
+) {
+    info!("Running headless, no window will be opened");
+    // we need a reference to an open gamepad for it to stay open
+    let mut opened_gamepads: HashMap<u32, sdl3::gamepad::Gamepad> = HashMap::new();
+    let mut held_buttons: HashMap<u32, Vec<Input>> = HashMap::new();
+    let mut controllers: HashMap<u32, ControllerInfo> = HashMap::new();
+    let mut keyboard_reader = EvdevReader::keyboards();
+    let mut gamepad_reader = EvdevReader::gamepads(proxy.map(Proxy::forwarder));
+    let mut app_state = AppState::AcceptingInput;
+    let mut activation = MasherActivation::new(&masher_state.masher);
+
+    // SDL is only needed when it is the one reading the controllers
+    let sdl_context = (!use_evdev).then(|| {
+        sdl3::hint::set("SDL_JOYSTICK_ALLOW_BACKGROUND_EVENTS", "1");
+        sdl3::init().unwrap()
+    });
+    let gamepad_system = sdl_context.as_ref().map(|sdl| sdl.gamepad().unwrap());
+    let mut event_pump = sdl_context.as_ref().map(|sdl| sdl.event_pump().unwrap());
+    loop {
+        let reloaded = apply_reloaded_settings(
+            settings_updates,
+            settings,
+            masher_state,
+            proxy.map(Arc::as_ref),
+        );
+        activation.tick(masher_state);
+        let keyboard_changed = handle_keyboard_events(
+            &mut keyboard_reader,
+            settings.keyboard_triggers,
+            &mut held_buttons,
+            &mut controllers,
+            &settings.axis_thresholds,
+        );
+        let gamepad_changed = handle_evdev_events(
+            &mut gamepad_reader,
+            use_evdev,
+            &mut held_buttons,
+            &mut controllers,
+            &settings.axis_thresholds,
+        );
+        if reloaded || keyboard_changed || gamepad_changed {
+            process_held_inputs(
+                &mut app_state,
+                &held_buttons,
+                &controllers,
+                settings,
+                settings_path,
+                masher_state,
+                &mut activation,
+            );
+        }
+
+        if let (Some(event_pump), Some(gamepad_system)) = (&mut event_pump, &gamepad_system) {
+            event_pump.pump_events();
+            for event in event_pump.poll_iter() {
+                if let Event::Quit { .. } = event {
+                    info!("Received quit, shutting down");
+                    masher_state.masher.send(MasherCommand::Shutdown);
+                    return;
+                }
+
+                if handle_gamepad_event(
+                    &event,
+                    gamepad_system,
+                    &mut opened_gamepads,
+                    &mut held_buttons,
+                    &mut controllers,
+                    &settings.axis_thresholds,
+                ) {
+                    process_held_inputs(
+                        &mut app_state,
+                        &held_buttons,
+                        &controllers,
+                        settings,
+                        settings_path,
+                        masher_state,
+                        &mut activation,
+                    );
+                }
+            }
+        }
+
+        // only poll at 2000 Hz
+        std::thread::sleep(std::time::Duration::from_micros(500));
+    }
+}
+
+/// Lines in the masher status panel
+const STATUS_PANEL_LINES: usize = 5;
+
+/// What the masher has reported this session, for the status panel
+#[derive(Default)]
+struct MasherStatus {
+    /// The game build HKCM is attached to, one of the process names in `CONFIGS`
+    build: Option<&'static str>,
+    dialogue_resolved: bool,
+    dialogue_visible: bool,
+    accepting_input: bool,
+    mashing: bool,
+    /// Dialogues mashed and presses sent since HKCM started
+    dialogues_mashed: u32,
+    presses: u64,
+}
+
+impl MasherStatus {
+    fn apply(&mut self, event: &MasherEvent) {
+        match event {
+            MasherEvent::Attached { build } => self.build = Some(build),
+            MasherEvent::Detached => {
+                self.build = None;
+                self.dialogue_resolved = false;
+                self.dialogue_visible = false;
+                self.accepting_input = false;
+                self.mashing = false;
+            }
+            MasherEvent::DialoguePointer { resolved } => self.dialogue_resolved = *resolved,
+            MasherEvent::DialogueDetected => self.dialogue_visible = true,
+            MasherEvent::DialogueClosed => self.dialogue_visible = false,
+            MasherEvent::AcceptingInput { accepting } => self.accepting_input = *accepting,
+            MasherEvent::MashingStarted => {
+                self.mashing = true;
+                self.dialogues_mashed += 1;
+            }
+            MasherEvent::MashingStopped { presses } => {
+                self.mashing = false;
+                self.presses += u64::from(*presses);
+            }
+            MasherEvent::Deactivated => {}
+        }
+    }
+
+    fn lines(&self) -> [String; STATUS_PANEL_LINES] {
+        let session = format!(
+            "Mashed {} dialogue{}, {} press{}",
+            self.dialogues_mashed,
+            if self.dialogues_mashed == 1 { "" } else { "s" },
+            self.presses,
+            if self.presses == 1 { "" } else { "es" }
+        );
+        let Some(build) = self.build else {
+            return [
+                "Hollow Knight: not running".to_string(),
+                "Build: -".to_string(),
+                "Dialogue: -".to_string(),
+                "Accepting input: -".to_string(),
+                session,
+            ];
+        };
+
+        let dialogue = if !self.dialogue_resolved {
+            "not found"
+        } else if self.mashing {
+            "mashing"
+        } else if self.dialogue_visible {
+            "visible"
+        } else {
+            "hidden"
+        };
+        [
+            "Hollow Knight: attached".to_string(),
+            format!("Build: {}", build),
+            format!("Dialogue: {}", dialogue),
+            format!(
+                "Accepting input: {}",
+                if self.accepting_input { "yes" } else { "no" }
+            ),
+            session,
+        ]
     }
 }
 
@@ -203,6 +817,166 @@ impl InputDisplay {
     }
 }
 
+/// The length of the chord being captured, if one is
+fn detecting_chord_length(app_state: &AppState) -> Option<usize> {
+    match app_state {
+        AppState::DetectConfig { chord_length, .. } => Some(*chord_length),
+        AppState::AcceptingInput => None,
+    }
+}
+
+fn adjust_chord_length(chord_length: usize, increase: bool) -> usize {
+    if increase {
+        (chord_length + 1).min(MAX_CHORD_LENGTH)
+    } else {
+        chord_length.saturating_sub(1).max(1)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SettingField {
+    ChordLength,
+    Rate,
+    DutyCycle,
+    ButtonGap,
+    Mode,
+    /// The toggle timeout, or the start delay in auto mode
+    ModeOption,
+}
+
+impl SettingField {
+    /// `chord_length` is shown instead of the profile's while a new chord is being captured
+    fn label(&self, profile: &Profile, chord_length: usize) -> String {
+        let timing = &profile.mash_timing;
+        match self {
+            SettingField::ChordLength => format!(
+                "Chord: {} button{}",
+                chord_length,
+                if chord_length == 1 { "" } else { "s" }
+            ),
+            SettingField::Rate => format!("Rate: {:.0} Hz", timing.rate),
+            SettingField::DutyCycle => format!("Hold: {:.0}%", timing.duty_cycle * 100.0),
+            SettingField::ButtonGap => format!("Gap: {} ms", timing.button_gap_ms),
+            SettingField::Mode => match profile.activation.mode {
+                ActivationMode::Hold => "Mode: hold".to_string(),
+                ActivationMode::Toggle => "Mode: toggle".to_string(),
+                ActivationMode::Auto => "Mode: auto".to_string(),
+            },
+            SettingField::ModeOption => match profile.activation.mode {
+                ActivationMode::Auto => {
+                    format!("Delay: {} ms", profile.activation.auto_start_delay_ms)
+                }
+                _ => match profile.activation.toggle_timeout_secs {
+                    0 => "Timeout: off".to_string(),
+                    secs => format!("Timeout: {} s", secs),
+                },
+            },
+        }
+    }
+
+    fn adjust(&self, profile: &mut Profile, increase: bool) {
+        let timing = &mut profile.mash_timing;
+        match self {
+            // The triggers have to match the chord length, so it only changes along with
+            // them once a chord has been captured, see `AppState::DetectConfig`
+            SettingField::ChordLength => {}
+            SettingField::Rate => {
+                let delta = if increase { 1.0 } else { -1.0 };
+                timing.rate = (timing.rate.round() + delta).clamp(MIN_MASH_RATE, MAX_MASH_RATE);
+            }
+            SettingField::DutyCycle => {
+                let delta = if increase { 0.05 } else { -0.05 };
+                // round to the nearest step so repeated presses don't accumulate float error
+                timing.duty_cycle = ((timing.duty_cycle + delta) * 20.0).round() / 20.0;
+                timing.duty_cycle = timing.duty_cycle.clamp(MIN_DUTY_CYCLE, 1.0);
+            }
+            SettingField::ButtonGap => {
+                timing.button_gap_ms = if increase {
+                    (timing.button_gap_ms + 5).min(MAX_BUTTON_GAP_MS)
+                } else {
+                    timing.button_gap_ms.saturating_sub(5)
+                };
+            }
+            SettingField::Mode => {
+                let modes = [
+                    ActivationMode::Hold,
+                    ActivationMode::Toggle,
+                    ActivationMode::Auto,
+                ];
+                let index = modes
+                    .iter()
+                    .position(|mode| *mode == profile.activation.mode)
+                    .unwrap_or(0);
+                let next = if increase {
+                    index + 1
+                } else {
+                    index + modes.len() - 1
+                };
+                profile.activation.mode = modes[next % modes.len()];
+            }
+            SettingField::ModeOption => {
+                let activation = &mut profile.activation;
+                if activation.mode == ActivationMode::Auto {
+                    activation.auto_start_delay_ms = if increase {
+                        (activation.auto_start_delay_ms + 100).min(MAX_AUTO_START_DELAY_MS)
+                    } else {
+                        activation.auto_start_delay_ms.saturating_sub(100)
+                    };
+                } else {
+                    activation.toggle_timeout_secs = if increase {
+                        (activation.toggle_timeout_secs + 10).min(MAX_TOGGLE_TIMEOUT_SECS)
+                    } else {
+                        activation.toggle_timeout_secs.saturating_sub(10)
+                    };
+                }
+            }
+        }
+    }
+}
+
+struct SettingControl {
+    field: SettingField,
+    label_position: (i32, i32),
+    decrease_button: Rect,
+    increase_button: Rect,
+}
+
+fn draw_text(
+    canvas: &mut sdl3::render::WindowCanvas,
+    texture_creator: &sdl3::render::TextureCreator<sdl3::video::WindowContext>,
+    font: &sdl3::ttf::Font,
+    text: &str,
+    x: i32,
+    y: i32,
+) {
+    draw_wrapped_text(canvas, texture_creator, font, text, x, y, 0);
+}
+
+/// Like `draw_text`, but also wraps lines longer than `wrap_width` pixels
+fn draw_wrapped_text(
+    canvas: &mut sdl3::render::WindowCanvas,
+    texture_creator: &sdl3::render::TextureCreator<sdl3::video::WindowContext>,
+    font: &sdl3::ttf::Font,
+    text: &str,
+    x: i32,
+    y: i32,
+    wrap_width: i32,
+) {
+    let surface = font
+        .render(text)
+        .blended_wrapped(Color::RGBA(250, 250, 250, 255), wrap_width)
+        .map_err(|e| e.to_string())
+        .unwrap();
+    let texture = texture_creator
+        .create_texture_from_surface(&surface)
+        .map_err(|e| e.to_string())
+        .unwrap();
+    let sdl3::render::TextureQuery { width, height, .. } = texture.query();
+    canvas
+        .copy(&texture, None, Rect::new(x, y, width, height))
+        .unwrap();
+}
+
 #[cfg(target_os = "windows")]
 fn toggle_masher_overlay(active: bool) -> Result<(), Box<dyn std::error::Error>> {
     let mut command = None;
@@ -263,9 +1037,29 @@ fn toggle_masher_overlay(active: bool) -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+/// Release builds on Windows start without a console, so options that only print or
+/// save the settings attach to the console HKCM was started from to show their output
+#[cfg(all(target_os = "windows", not(debug_assertions)))]
+fn attach_parent_console() {
+    use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+    // Fails when HKCM wasn't started from a console, there is nothing to print to then
+    let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
 fn main() {
-    let mut base_path = dirs::data_dir().unwrap();
-    base_path.push("HKCM");
+    let cli = Cli::parse();
+
+    #[cfg(all(target_os = "windows", not(debug_assertions)))]
+    if cli.print_config || cli.reset_config || cli.command.is_some() {
+        attach_parent_console();
+    }
+
+    let base_path = cli.data_dir.clone().unwrap_or_else(|| {
+        let mut path = dirs::data_dir().unwrap();
+        path.push("HKCM");
+        path
+    });
     std::fs::create_dir_all(&base_path).unwrap();
 
     let mut log_file_path = base_path.clone();
@@ -289,9 +1083,10 @@ fn main() {
         .unwrap();
 
     #[cfg(debug_assertions)]
-    let log_level = LevelFilter::Debug;
+    let default_log_level = LevelFilter::Debug;
     #[cfg(not(debug_assertions))]
-    let log_level = LevelFilter::Info;
+    let default_log_level = LevelFilter::Info;
+    let log_level = cli.log_level.unwrap_or(default_log_level);
 
     let config = Config::builder()
         .appender(Appender::builder().build("console", Box::new(console_log_appender)))
@@ -308,256 +1103,191 @@ fn main() {
 
     let mut current_app_state = AppState::AcceptingInput;
     // Read from settings file
-    let mut settings_path = base_path.clone();
-    settings_path.push("HKCM_settings.json");
-
-    #[cfg(target_os = "windows")]
-    let default_config = Settings {
-        mashing_triggers: vec![
-            VigemInput::Button(XButtons::X),
-            VigemInput::Button(XButtons::A),
-            VigemInput::Button(XButtons::B),
-        ],
-    };
+    let settings_path: PathBuf = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| base_path.join("HKCM_settings.json"));
+
+    // Printing on its own only inspects the settings, so the file is never
+    // created, migrated or backed up here
+    if cli.print_config && !cli.reset_config && cli.command.is_none() {
+        let mut settings = if settings_path.exists() {
+            match read_settings(&settings_path) {
+                Ok(settings) => settings,
+                Err(err) => {
+                    error!("Invalid settings in {}: {}", settings_path.display(), err);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            Settings::default()
+        };
+        if let Some(profile) = &cli.profile {
+            if !settings.select_profile(profile) {
+                error!("No profile named {:?}", profile);
+                std::process::exit(1);
+            }
+        }
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&settings).expect("Failed to convert config to json")
+        );
+        return;
+    }
 
-    #[cfg(target_os = "linux")]
-    let default_config = Settings {
-        mashing_triggers: vec![
-            Controller::GamePad(GamePad::East),
-            Controller::GamePad(GamePad::South),
-            Controller::GamePad(GamePad::West),
-        ],
-    };
+    if cli.reset_config {
+        save_settings(&Settings::default(), &settings_path);
+        info!("Reset settings in {}", settings_path.display());
+    }
 
-    let mut settings: Settings = if !settings_path.exists() {
-        let json = serde_json::to_string_pretty(&default_config)
-            .expect("Failed to convert config to json");
-        let mut file = File::create(&settings_path).unwrap();
-        file.write_all(json.as_bytes())
-            .expect("Failed to write config to file");
+    let (mut settings, settings_error) = load_settings(&settings_path);
 
-        default_config
-    } else {
-        let file = File::open(&settings_path).unwrap();
+    if let Some(profile) = &cli.profile {
+        if !settings.select_profile(profile) {
+            let names: Vec<&str> = settings.profiles.iter().map(|p| p.name.as_str()).collect();
+            error!(
+                "No profile named {:?}, available profiles: {}",
+                profile,
+                names.join(", ")
+            );
+            std::process::exit(1);
+        }
+        save_settings(&settings, &settings_path);
+        info!("Using profile {:?}", profile);
+    }
 
-        serde_json::from_reader(file).unwrap_or_else(|_| {
-            error!("Failed to parse settings from config file");
-            default_config
-        })
-    };
+    if let Some(Command::SetTriggers { buttons }) = &cli.command {
+        let mut triggers = Vec::new();
+        for name in buttons {
+            let Some(input) = Input::from_name(name) else {
+                error!("Unknown or unsupported trigger button: {}", name);
+                std::process::exit(1);
+            };
+            if !triggers.contains(&input) {
+                triggers.push(input);
+            }
+        }
+        if triggers.len() > MAX_CHORD_LENGTH {
+            error!("At most {} trigger buttons are supported", MAX_CHORD_LENGTH);
+            std::process::exit(1);
+        }
 
-    // App state setup
-    sdl3::hint::set("SDL_JOYSTICK_ALLOW_BACKGROUND_EVENTS", "1");
-    let sdl_context = sdl3::init().unwrap();
-    let gamepad_system = sdl_context.gamepad().unwrap();
-    // we need a reference to an open gamepad for it to stay open
-    let mut _opened_gamepads: HashMap<u32, sdl3::gamepad::Gamepad> = HashMap::new();
-
-    #[cfg(target_os = "windows")]
-    let mut held_buttons: HashMap<u32, Vec<VigemInput>> = HashMap::new();
-
-    #[cfg(target_os = "windows")]
-    let mashing_buttons: Arc<RwLock<Vec<VigemInput>>> =
-        Arc::new(std::sync::RwLock::new(settings.mashing_triggers.clone()));
-
-    #[cfg(target_os = "windows")]
-    {
-        let thread_mashing_buttons = Arc::clone(&mashing_buttons);
-
-        thread::spawn(move || {
-            // VIGEM setup
-            let client = vigem_client::Client::connect().unwrap();
-            let id = vigem_client::TargetId::XBOX360_WIRED;
-            let mut target = vigem_client::Xbox360Wired::new(client, id);
-            target
-                .plugin()
-                .expect("Failed to plugin virtual controller");
-            target
-                .wait_ready()
-                .expect("Could not wait for virtual controller to ready");
-
-            text_masher(
-                |key_to_press| {
-                    let mut gamepad_state = vigem_client::XGamepad::default();
-
-                    if key_to_press < MAX_MASHING_KEY_COUNT {
-                        let mash_buttons = thread_mashing_buttons.read().unwrap();
-                        if let Some(press) = mash_buttons.get(key_to_press as usize) {
-                            match press {
-                                VigemInput::Button(b) => gamepad_state.buttons = XButtons(*b),
-                                VigemInput::LeftTrigger => gamepad_state.left_trigger = u8::MAX,
-                                VigemInput::RightTrigger => gamepad_state.right_trigger = u8::MAX,
-                            }
-                        }
-                    }
+        let profile = settings.active_mut();
+        profile.chord_length = triggers.len();
+        profile.mashing_triggers = triggers;
+        if let Err(err) = settings.validate() {
+            error!("Can't use these triggers, {}", err);
+            std::process::exit(1);
+        }
+        save_settings(&settings, &settings_path);
+        info!(
+            "Triggers for profile {:?} written to {}",
+            settings.active_profile,
+            settings_path.display()
+        );
+    }
 
-                    target
-                        .update(&gamepad_state)
-                        .expect("Failed to update virtual controller while mashing");
-                },
-                toggle_masher_overlay,
-            );
-        });
+    if cli.print_config {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&settings).expect("Failed to convert config to json")
+        );
     }
 
-    #[cfg(target_os = "linux")]
-    let mut held_buttons: HashMap<u32, Vec<Controller>> = HashMap::new();
-
-    #[cfg(target_os = "linux")]
-    let mashing_buttons: Arc<RwLock<Vec<Controller>>> =
-        Arc::new(std::sync::RwLock::new(settings.mashing_triggers.clone()));
-
-    #[cfg(target_os = "linux")]
-    {
-        let thread_mashing_buttons = Arc::clone(&mashing_buttons);
-
-        thread::spawn(move || {
-            let mut controller = uinput::default()
-                .unwrap()
-                .name("Overbind Virtual Gamepad")
-                .unwrap()
-                .event(Absolute(uinput::event::absolute::Absolute::Position(
-                    Position::X,
-                )))
-                .unwrap()
-                .min(-32768)
-                .max(32767)
-                .fuzz(0)
-                .flat(0)
-                .event(Absolute(uinput::event::absolute::Absolute::Position(
-                    Position::Y,
-                )))
-                .unwrap()
-                .min(-32768)
-                .max(32767)
-                .fuzz(0)
-                .flat(0)
-                .event(Absolute(uinput::event::absolute::Absolute::Position(
-                    Position::RX,
-                )))
-                .unwrap()
-                .min(-32768)
-                .max(32767)
-                .fuzz(0)
-                .flat(0)
-                .event(Absolute(uinput::event::absolute::Absolute::Position(
-                    Position::RY,
-                )))
-                .unwrap()
-                .min(-32768)
-                .max(32767)
-                .fuzz(0)
-                .flat(0)
-                .event(Absolute(uinput::event::absolute::Absolute::Hat(Hat::X0)))
-                .unwrap()
-                .min(-1)
-                .max(1)
-                .fuzz(0)
-                .flat(0)
-                .event(Absolute(uinput::event::absolute::Absolute::Hat(Hat::Y0)))
-                .unwrap()
-                .min(-1)
-                .max(1)
-                .fuzz(0)
-                .flat(0)
-                .event(Absolute(uinput::event::absolute::Absolute::Position(
-                    Position::Z,
-                )))
-                .unwrap()
-                .min(0)
-                .max(1023)
-                .fuzz(0)
-                .flat(0)
-                .event(Absolute(uinput::event::absolute::Absolute::Position(
-                    Position::RZ,
-                )))
-                .unwrap()
-                .min(0)
-                .max(1023)
-                .fuzz(0)
-                .flat(0)
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::North),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::South),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::East),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::West),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::TL),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::TR),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::ThumbL),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::ThumbR),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::Select),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::Start),
-                ))
-                .unwrap()
-                .event(uinput::Event::Controller(
-                    uinput::event::Controller::GamePad(GamePad::Mode),
-                ))
-                .unwrap()
-                .create()
-                .unwrap();
-
-            text_masher(
-                |key_to_press| {
-                    let mut send_face_button_event = |button: &Controller, key_is_down: bool| {
-                        if key_is_down {
-                            controller
-                                .press(button)
-                                .expect("Failed to press virtual controller button while mashing");
-                        } else {
-                            controller.release(button).expect(
-                                "Failed to release virtual controller button while mashing",
-                            );
-                        }
-                    };
+    if cli.reset_config || cli.print_config || cli.command.is_some() {
+        return;
+    }
 
-                    if key_to_press < MAX_MASHING_KEY_COUNT {
-                        let mash_buttons = thread_mashing_buttons.read().unwrap();
-                        mash_buttons.iter().enumerate().for_each(|(index, button)| {
-                            send_face_button_event(button, key_to_press == index as u8);
-                        });
-                    }
+    let (masher, masher_handle) = Masher::new(masher_config(settings.active()));
+    let masher_state = MasherState::new(settings.active(), masher_handle);
+    let settings_updates = watch_settings(settings_path.clone());
 
-                    controller
-                        .synchronize()
-                        .expect("Failed to update virtual controller while mashing");
-                },
-                toggle_masher_overlay,
-            );
-        });
+    let use_proxy = settings.proxy.enabled && cfg!(target_os = "linux");
+    if settings.proxy.enabled && !use_proxy {
+        warn!("Proxy mode is only supported on Linux, leaving controllers alone");
+    }
+    // Without the proxy the game reads the physical controller itself, and HKCM
+    // can't take back presses the game has already seen
+    if settings.proxy.neutralize_while_mashing && !cfg!(target_os = "linux") {
+        warn!("neutralize_while_mashing only works on Linux, physical controllers will still reach the game while mashing");
+    } else if settings.proxy.neutralize_while_mashing && !use_proxy {
+        warn!(
+            "neutralize_while_mashing needs proxy mode, set \"enabled\": true in proxy to use it"
+        );
+    }
+    // The proxy needs the controllers read through evdev so it can grab them
+    let use_evdev = (cli.evdev || use_proxy) && cfg!(target_os = "linux");
+    if cli.evdev && !use_evdev {
+        warn!("--evdev is only supported on Linux, reading controllers through SDL");
+    }
+
+    let shared_pad = use_proxy
+        .then(|| SharedPad::new(proxy_pad().expect("Failed to create virtual controller")));
+    let proxy = shared_pad.as_ref().map(|pad| {
+        info!("Proxy mode, controllers are passed through the virtual controller");
+        Proxy::new(
+            pad.with_source(PadSource::Proxy),
+            &settings,
+            Arc::clone(&masher_state.mashing_buttons),
+        )
+    });
+
+    let thread_proxy = proxy.clone();
+    let on_mashing = move |mashing| {
+        if let Some(proxy) = &thread_proxy {
+            proxy.set_mashing(mashing);
+        }
+        toggle_masher_overlay(mashing)
+    };
+    let (pad_ready, pad_created) = mpsc::channel();
+    thread::spawn(move || {
+        if let Some(mut pad) = shared_pad {
+            let _ = pad_ready.send(());
+            masher.run(HollowKnightState::attach, &mut pad, on_mashing);
+        } else {
+            let mut pad = platform_pad().expect("Failed to create virtual controller");
+            let _ = pad_ready.send(());
+            masher.run(HollowKnightState::attach, pad.as_mut(), on_mashing);
+        }
+    });
+    // The ViGEm pad is only recognised by its XInput slot, which has to be known before
+    // SDL reports the pad
+    let _ = pad_created.recv();
+
+    if cli.headless {
+        run_headless(
+            use_evdev,
+            &mut settings,
+            &settings_path,
+            &settings_updates,
+            &masher_state,
+            proxy.as_ref(),
+        );
+        return;
     }
 
+    // we need a reference to an open gamepad for it to stay open
+    let mut opened_gamepads: HashMap<u32, sdl3::gamepad::Gamepad> = HashMap::new();
+    let mut held_buttons: HashMap<u32, Vec<Input>> = HashMap::new();
+    let mut controllers: HashMap<u32, ControllerInfo> = HashMap::new();
+    let mut keyboard_reader = EvdevReader::keyboards();
+    let mut gamepad_reader = EvdevReader::gamepads(proxy.as_ref().map(Proxy::forwarder));
+
+    // App state setup
+    sdl3::hint::set("SDL_JOYSTICK_ALLOW_BACKGROUND_EVENTS", "1");
+    let sdl_context = sdl3::init().unwrap();
+    let gamepad_system = (!use_evdev).then(|| sdl_context.gamepad().unwrap());
+
     // Initialize GUI
+    // Leave room below the controls to explain why the settings were reset
+    let error_banner = settings_error
+        .as_ref()
+        .map(|_| Rect::new(10, 750, 300, 100));
+    let window_height = 750 + error_banner.map_or(0, |banner| banner.height() + 10);
     let video_subsystem = sdl_context.video().unwrap();
 
     let window = video_subsystem
-        .window("HKCM", 320, 300)
+        .window("HKCM", 320, window_height)
         .position_centered()
         .build()
         .unwrap();
@@ -600,10 +1330,7 @@ fn main() {
 
     let mut input_display_boxes = HashMap::new();
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::LeftTrigger,
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::TL2),
+        Input::LeftTrigger,
         InputDisplay {
             rect: Rect::new(
                 input_display_x,
@@ -614,10 +1341,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::RightTrigger,
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::TR2),
+        Input::RightTrigger,
         InputDisplay {
             rect: Rect::new(
                 right_x_offset + face_button_width as i32,
@@ -628,10 +1352,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::LB),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::TL),
+        Input::Button(gamepad::Button::LeftShoulder),
         InputDisplay {
             rect: Rect::new(
                 input_display_x,
@@ -642,10 +1363,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::RB),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::TR),
+        Input::Button(gamepad::Button::RightShoulder),
         InputDisplay {
             rect: Rect::new(
                 right_x_offset + face_button_width as i32,
@@ -656,9 +1374,8 @@ fn main() {
         },
     );
 
-    #[cfg(target_os = "windows")]
     input_display_boxes.insert(
-        VigemInput::Button(XButtons::UP),
+        Input::Button(gamepad::Button::DPadUp),
         InputDisplay {
             rect: Rect::new(
                 input_display_x + face_button_width as i32,
@@ -668,9 +1385,8 @@ fn main() {
             ),
         },
     );
-    #[cfg(target_os = "windows")]
     input_display_boxes.insert(
-        VigemInput::Button(XButtons::RIGHT),
+        Input::Button(gamepad::Button::DPadRight),
         InputDisplay {
             rect: Rect::new(
                 input_display_x + 2 * face_button_width as i32,
@@ -680,9 +1396,8 @@ fn main() {
             ),
         },
     );
-    #[cfg(target_os = "windows")]
     input_display_boxes.insert(
-        VigemInput::Button(XButtons::DOWN),
+        Input::Button(gamepad::Button::DPadDown),
         InputDisplay {
             rect: Rect::new(
                 input_display_x + face_button_width as i32,
@@ -692,9 +1407,8 @@ fn main() {
             ),
         },
     );
-    #[cfg(target_os = "windows")]
     input_display_boxes.insert(
-        VigemInput::Button(XButtons::LEFT),
+        Input::Button(gamepad::Button::DPadLeft),
         InputDisplay {
             rect: Rect::new(
                 input_display_x,
@@ -706,10 +1420,7 @@ fn main() {
     );
 
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::BACK),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::Select),
+        Input::Button(gamepad::Button::Back),
         InputDisplay {
             rect: Rect::new(
                 middle_buttons_x_offset,
@@ -720,10 +1431,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::GUIDE),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::Mode),
+        Input::Button(gamepad::Button::Guide),
         InputDisplay {
             rect: Rect::new(
                 middle_buttons_x_offset + 2 * middle_button_width as i32,
@@ -734,10 +1442,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::START),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::Start),
+        Input::Button(gamepad::Button::Start),
         InputDisplay {
             rect: Rect::new(
                 middle_buttons_x_offset + 2 * 2 * middle_button_width as i32,
@@ -749,10 +1454,7 @@ fn main() {
     );
 
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::Y),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::North),
+        Input::Button(gamepad::Button::North),
         InputDisplay {
             rect: Rect::new(
                 right_x_offset + face_button_width as i32,
@@ -763,10 +1465,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::B),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::East),
+        Input::Button(gamepad::Button::East),
         InputDisplay {
             rect: Rect::new(
                 right_x_offset + 2 * face_button_width as i32,
@@ -777,10 +1476,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::A),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::South),
+        Input::Button(gamepad::Button::South),
         InputDisplay {
             rect: Rect::new(
                 right_x_offset + face_button_width as i32,
@@ -791,10 +1487,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::X),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::West),
+        Input::Button(gamepad::Button::West),
         InputDisplay {
             rect: Rect::new(
                 right_x_offset,
@@ -807,10 +1500,7 @@ fn main() {
 
     let thumbstick_button_y_offset = face_button_y_offset + 3 * face_button_width as i32;
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::LTHUMB),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::ThumbL),
+        Input::Button(gamepad::Button::LeftStick),
         InputDisplay {
             rect: Rect::new(
                 input_display_x + 3 * face_button_width as i32,
@@ -821,10 +1511,7 @@ fn main() {
         },
     );
     input_display_boxes.insert(
-        #[cfg(target_os = "windows")]
-        VigemInput::Button(XButtons::RTHUMB),
-        #[cfg(target_os = "linux")]
-        Controller::GamePad(GamePad::ThumbR),
+        Input::Button(gamepad::Button::RightStick),
         InputDisplay {
             rect: Rect::new(
                 right_x_offset - face_button_width as i32,
@@ -889,27 +1576,129 @@ fn main() {
         cancel_height,
     );
 
-    let guide_text_surface = small_font
-        .render("Hold 3 buttons\nto configure\nmasher triggers.")
-        .blended_wrapped(Color::RGBA(250, 250, 250, 255), 0)
-        .map_err(|e| e.to_string())
-        .unwrap();
-    let guide_texture = texture_creator
-        .create_texture_from_surface(&guide_text_surface)
-        .map_err(|e| e.to_string())
-        .unwrap();
-    let sdl3::render::TextureQuery {
-        width: guide_width,
-        height: guide_height,
-        ..
-    } = guide_texture.query();
     let guide_x = config_button_background.x() + config_button_background.width() as i32 + 8;
-    let guide_text = Rect::new(guide_x, config_button_y_offset, guide_width, guide_height);
+
+    // Define chord length and mash timing controls
+    let setting_row_height: i32 = 30;
+    let setting_button_width: u32 = 24;
+    let setting_controls_y_offset =
+        config_button_y_offset + config_button_background.height() as i32 + 15;
+    let setting_controls: Vec<SettingControl> = [
+        SettingField::ChordLength,
+        SettingField::Rate,
+        SettingField::DutyCycle,
+        SettingField::ButtonGap,
+        SettingField::Mode,
+        SettingField::ModeOption,
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(row, field)| {
+        let row_y = setting_controls_y_offset + row as i32 * setting_row_height;
+        SettingControl {
+            field,
+            label_position: (input_display_x, row_y + 2),
+            decrease_button: Rect::new(
+                right_x_offset,
+                row_y,
+                setting_button_width,
+                setting_button_width,
+            ),
+            increase_button: Rect::new(
+                right_x_offset + setting_button_width as i32 + 6,
+                row_y,
+                setting_button_width,
+                setting_button_width,
+            ),
+        }
+    })
+    .collect();
+
+    // Define profile dropdown, the list opens upwards over the setting controls
+    let profile_dropdown = Rect::new(
+        input_display_x,
+        setting_controls_y_offset + setting_controls.len() as i32 * setting_row_height + 6,
+        280,
+        setting_button_width,
+    );
+    let profile_option = |index: usize, count: usize| {
+        Rect::new(
+            profile_dropdown.x(),
+            profile_dropdown.y() - (count - index) as i32 * profile_dropdown.height() as i32,
+            profile_dropdown.width(),
+            profile_dropdown.height(),
+        )
+    };
+    let mut profile_dropdown_open = false;
+
+    // Define the connected controller list, one row each below a heading
+    let controller_row_height: i32 = 22;
+    let controller_list_y = profile_dropdown.y() + profile_dropdown.height() as i32 + 10;
+    let controller_row = |index: usize| {
+        Rect::new(
+            input_display_x,
+            controller_list_y + (index as i32 + 1) * controller_row_height,
+            280,
+            controller_row_height as u32 - 2,
+        )
+    };
+    // Define the masher status panel below the controller list
+    let status_line_height: i32 = 20;
+    let status_panel = Rect::new(
+        input_display_x,
+        controller_row(MAX_LISTED_CONTROLLERS).y() + 6,
+        280,
+        (STATUS_PANEL_LINES as i32 * status_line_height + 8) as u32,
+    );
+    let mut activation = MasherActivation::new(&masher_state.masher);
+
+    let masher_events = masher_state.masher.subscribe();
+    let mut masher_status = MasherStatus::default();
 
     info!("Initialization complete");
     let mut new_input = true;
     let mut event_pump = sdl_context.event_pump().unwrap();
     'mainloop: loop {
+        let reloaded = apply_reloaded_settings(
+            &settings_updates,
+            &mut settings,
+            &masher_state,
+            proxy.as_deref(),
+        );
+        activation.tick(&masher_state);
+        for event in masher_events.try_iter() {
+            masher_status.apply(&event);
+            new_input = true;
+        }
+
+        let keyboard_changed = handle_keyboard_events(
+            &mut keyboard_reader,
+            settings.keyboard_triggers,
+            &mut held_buttons,
+            &mut controllers,
+            &settings.axis_thresholds,
+        );
+        let gamepad_changed = handle_evdev_events(
+            &mut gamepad_reader,
+            use_evdev,
+            &mut held_buttons,
+            &mut controllers,
+            &settings.axis_thresholds,
+        );
+        // A reloaded chord may already be held, so it is checked without waiting for input
+        if reloaded || keyboard_changed || gamepad_changed {
+            new_input = true;
+            process_held_inputs(
+                &mut current_app_state,
+                &held_buttons,
+                &controllers,
+                &mut settings,
+                &settings_path,
+                &masher_state,
+                &mut activation,
+            );
+        }
+
         event_pump.pump_events();
         for event in event_pump.poll_iter() {
             match event {
@@ -925,154 +1714,139 @@ fn main() {
                     {
                         new_input = true;
 
-                        if config_button_background
-                            .contains_point(sdl3::rect::Point::new(x as i32, y as i32))
-                        {
-                            if matches!(current_app_state, AppState::AcceptingInput) {
-                                info!("Detecting mashing configuration");
-                                current_app_state = AppState::DetectConfig;
-                            } else if matches!(current_app_state, AppState::DetectConfig) {
-                                info!("Cancel detection");
-                                current_app_state = AppState::AcceptingInput;
-                            }
-                        }
-                    }
-                }
-                Event::ControllerDeviceAdded { which, .. } => {
-                    if let Ok(gamepad) = gamepad_system.open(which) {
-                        _opened_gamepads.insert(which, gamepad);
-                    }
-                }
-                Event::ControllerDeviceRemoved { which, .. } => {
-                    _opened_gamepads.remove(&which);
-                }
-                Event::ControllerButtonDown { which, button, .. } => {
-                    debug!("controller down {}", button.string());
-
-                    new_input = true;
-                    if let Some(input) = sdl_button_to_input(button) {
-                        if !held_buttons.contains_key(&which) {
-                            held_buttons.insert(which, vec![input]);
-                        } else {
-                            if let Some(held) = held_buttons.get_mut(&which) {
-                                if !held.iter().any(|x| *x == input) {
-                                    held.push(input);
+                        let click_point = sdl3::rect::Point::new(x as i32, y as i32);
+
+                        // An open dropdown takes the next click, wherever it lands
+                        if profile_dropdown_open {
+                            profile_dropdown_open = false;
+                            let count = settings.profiles.len();
+                            let clicked = (0..count).find(|index| {
+                                profile_option(*index, count).contains_point(click_point)
+                            });
+                            if let Some(index) = clicked {
+                                let name = settings.profiles[index].name.clone();
+                                if name != settings.active_profile {
+                                    settings.select_profile(&name);
+                                    masher_state.apply(settings.active());
+                                    save_settings(&settings, &settings_path);
+                                    info!("Switched to profile {:?}", name);
                                 }
                             }
+                            continue;
                         }
-                    }
-                }
-                Event::ControllerButtonUp { which, button, .. } => {
-                    debug!("controller up {}", button.string());
-
-                    new_input = true;
-                    if let Some(entry) = held_buttons.get_mut(&which) {
-                        if let Some(input) = sdl_button_to_input(button) {
-                            entry.retain(|held| *held != input);
-
-                            if entry.is_empty() {
-                                held_buttons.remove_entry(&which);
-                            }
+                        if profile_dropdown.contains_point(click_point) {
+                            profile_dropdown_open = true;
+                            continue;
                         }
-                    }
-                }
-
-                Event::ControllerAxisMotion {
-                    which, axis, value, ..
-                } => {
-                    #[cfg(target_os = "windows")]
-                    let converted_input = match axis {
-                        gamepad::Axis::TriggerLeft => Some(VigemInput::LeftTrigger),
-                        gamepad::Axis::TriggerRight => Some(VigemInput::RightTrigger),
-                        _ => None,
-                    };
-
-                    #[cfg(target_os = "linux")]
-                    let converted_input = match axis {
-                        gamepad::Axis::TriggerLeft => Some(Controller::GamePad(GamePad::TL2)),
-                        gamepad::Axis::TriggerRight => Some(Controller::GamePad(GamePad::TR2)),
-                        _ => None,
-                    };
 
-                    if let Some(input) = converted_input {
-                        new_input = true;
+                        let clicked_controller = sorted_controllers(&controllers)
+                            .into_iter()
+                            .take(MAX_LISTED_CONTROLLERS)
+                            .enumerate()
+                            .find(|(index, _)| controller_row(*index).contains_point(click_point))
+                            .map(|(_, (_, info))| info.clone());
+                        if let Some(info) = clicked_controller {
+                            settings.toggle_allowed_controller(&info);
+                            save_settings(&settings, &settings_path);
+                            info!(
+                                "{} can trigger HKCM: {}",
+                                info.name,
+                                settings.is_controller_allowed(&info)
+                            );
+                            continue;
+                        }
 
-                        if value > 0 {
-                            if !held_buttons.contains_key(&which) {
-                                held_buttons.insert(which, vec![input]);
+                        for control in &setting_controls {
+                            let increase = if control.increase_button.contains_point(click_point) {
+                                true
+                            } else if control.decrease_button.contains_point(click_point) {
+                                false
                             } else {
-                                if let Some(held) = held_buttons.get_mut(&which) {
-                                    if !held.iter().any(|x| *x == input) {
-                                        held.push(input);
+                                continue;
+                            };
+
+                            if let SettingField::ChordLength = control.field {
+                                let chord_length = adjust_chord_length(
+                                    detecting_chord_length(&current_app_state)
+                                        .unwrap_or(settings.active().chord_length),
+                                    increase,
+                                );
+                                let already_held = match current_app_state {
+                                    AppState::DetectConfig { already_held, .. } => already_held,
+                                    AppState::AcceptingInput => {
+                                        chord_held_inputs(&held_buttons, &settings, &controllers)
                                     }
-                                }
+                                };
+                                current_app_state = AppState::DetectConfig {
+                                    already_held,
+                                    chord_length,
+                                };
+                                info!(
+                                    "Detecting a mashing configuration of {} buttons",
+                                    chord_length
+                                );
+                                continue;
                             }
-                        } else {
-                            if let Some(entry) = held_buttons.get_mut(&which) {
-                                entry.retain(|held| *held != input);
 
-                                if entry.is_empty() {
-                                    held_buttons.remove_entry(&which);
-                                }
+                            control.field.adjust(settings.active_mut(), increase);
+                            masher_state.apply(settings.active());
+                            save_settings(&settings, &settings_path);
+                            info!(
+                                "Chord length set to {}, mash timing set to {:?}",
+                                settings.active().chord_length,
+                                settings.active().mash_timing
+                            );
+                        }
+
+                        if config_button_background.contains_point(click_point) {
+                            if matches!(current_app_state, AppState::AcceptingInput) {
+                                info!("Detecting mashing configuration");
+                                current_app_state = AppState::DetectConfig {
+                                    already_held: chord_held_inputs(
+                                        &held_buttons,
+                                        &settings,
+                                        &controllers,
+                                    ),
+                                    chord_length: settings.active().chord_length,
+                                };
+                            } else if matches!(current_app_state, AppState::DetectConfig { .. }) {
+                                info!("Cancel detection");
+                                current_app_state = AppState::AcceptingInput;
                             }
                         }
                     }
                 }
-
                 Event::Quit { .. } => {
-                    SHOULD_TERMINATE_MASHER.store(true, Ordering::SeqCst);
+                    masher_state.masher.send(MasherCommand::Shutdown);
                     break 'mainloop;
                 }
-                _ => (),
-            }
-
-            // the mashing controller will never be holding all 3 so there
-            // isnt risk of a feedback loop
-            // config just needs to hold the mashing keys, and any controller
-            // can press them to activate the masher
-            if matches!(current_app_state, AppState::AcceptingInput) {
-                let mut should_mash = false;
-                for (_, val) in held_buttons.iter() {
-                    if val.len() >= MAX_MASHING_KEY_COUNT as usize {
-                        // check if all triggers are pressed and activate the mashing
-                        should_mash = mashing_buttons
-                            .read()
-                            .unwrap()
-                            .iter()
-                            .all(|button| val.contains(button));
-                        if should_mash {
-                            break;
-                        };
-                    }
-                }
-
-                if IS_MASHER_ACTIVE.load(Ordering::SeqCst) != should_mash {
-                    debug!("all mashing triggers pressed: {}", should_mash);
-                    IS_MASHER_ACTIVE.store(should_mash, Ordering::SeqCst);
-                }
-            } else if matches!(current_app_state, AppState::DetectConfig) {
-                for (_, val) in held_buttons.iter() {
-                    if val.len() == MAX_MASHING_KEY_COUNT as usize {
-                        {
-                            *mashing_buttons
-                                .write()
-                                .expect("Failed to get state while storing config") = val.clone();
+                _ => {
+                    if let Some(gamepad_system) = &gamepad_system {
+                        if handle_gamepad_event(
+                            &event,
+                            gamepad_system,
+                            &mut opened_gamepads,
+                            &mut held_buttons,
+                            &mut controllers,
+                            &settings.axis_thresholds,
+                        ) {
+                            new_input = true;
                         }
-
-                        current_app_state = AppState::AcceptingInput;
-
-                        settings.mashing_triggers = val.clone();
-
-                        let json = serde_json::to_string_pretty(&settings)
-                            .expect("Failed to convert config to json");
-                        let mut file = File::create(&settings_path).unwrap();
-                        file.write_all(json.as_bytes())
-                            .expect("Failed to write config to file");
-                        info!("Config set, now accepting input");
-                        continue 'mainloop;
                     }
                 }
             }
+
+            if process_held_inputs(
+                &mut current_app_state,
+                &held_buttons,
+                &controllers,
+                &mut settings,
+                &settings_path,
+                &masher_state,
+                &mut activation,
+            ) {
+                new_input = true;
+            }
         }
 
         // Render GUI
@@ -1090,7 +1864,7 @@ fn main() {
                 canvas
                     .copy(&configure_texture, None, config_button_text)
                     .unwrap();
-            } else if matches!(current_app_state, AppState::DetectConfig) {
+            } else if matches!(current_app_state, AppState::DetectConfig { .. }) {
                 canvas.set_draw_color(Color::RGB(93, 114, 152));
                 canvas
                     .fill_rect(config_button_background)
@@ -1098,14 +1872,56 @@ fn main() {
                 canvas
                     .copy(&cancel_texture, None, cancel_button_text)
                     .unwrap();
-                canvas.copy(&guide_texture, None, guide_text).unwrap();
+                let chord_length = detecting_chord_length(&current_app_state)
+                    .unwrap_or(settings.active().chord_length);
+                let plural = if chord_length == 1 { "" } else { "s" };
+                draw_text(
+                    &mut canvas,
+                    &texture_creator,
+                    &small_font,
+                    &format!(
+                        "Hold {} button{}\nto configure\nmasher triggers.",
+                        chord_length, plural
+                    ),
+                    guide_x,
+                    config_button_y_offset,
+                );
+            }
+
+            // Draw mash timing controls
+            for control in &setting_controls {
+                draw_text(
+                    &mut canvas,
+                    &texture_creator,
+                    &small_font,
+                    &control.field.label(
+                        settings.active(),
+                        detecting_chord_length(&current_app_state)
+                            .unwrap_or(settings.active().chord_length),
+                    ),
+                    control.label_position.0,
+                    control.label_position.1,
+                );
+
+                canvas.set_draw_color(Color::RGB(70, 87, 117));
+                for (button, symbol) in [
+                    (control.decrease_button, "-"),
+                    (control.increase_button, "+"),
+                ] {
+                    canvas.fill_rect(button).expect("Failed rendering button");
+                    draw_text(
+                        &mut canvas,
+                        &texture_creator,
+                        &small_font,
+                        symbol,
+                        button.x() + 7,
+                        button.y() + 1,
+                    );
+                }
             }
 
             // Draw input display
-            #[cfg(target_os = "windows")]
-            let mut max_held: Option<&Vec<VigemInput>> = None;
-            #[cfg(target_os = "linux")]
-            let mut max_held: Option<&Vec<Controller>> = None;
+            let mut max_held: Option<&Vec<Input>> = None;
             let mut max_len: usize = 0;
             for (_, val) in held_buttons.iter() {
                 if val.len() > max_len {
@@ -1126,12 +1942,140 @@ fn main() {
             }
 
             // Outline configured mashing triggers
-            for mashing_button in mashing_buttons.read().unwrap().iter() {
+            for mashing_button in masher_state.mashing_buttons.read().unwrap().iter() {
                 if let Some(display) = input_display_boxes.get_mut(mashing_button) {
                     display.outline(&mut canvas);
                 }
             }
 
+            // Draw connected controllers and whether they can trigger HKCM
+            let listed = sorted_controllers(&controllers);
+            let heading = if listed.is_empty() {
+                "No controllers connected"
+            } else if settings.allowed_controllers.is_empty() {
+                "Controllers, click to limit triggers to one:"
+            } else {
+                "Controllers, only ticked ones trigger:"
+            };
+            draw_text(
+                &mut canvas,
+                &texture_creator,
+                &small_font,
+                heading,
+                input_display_x,
+                controller_list_y,
+            );
+            for (index, (which, info)) in listed.iter().take(MAX_LISTED_CONTROLLERS).enumerate() {
+                let row = controller_row(index);
+                canvas.set_draw_color(Color::RGB(70, 87, 117));
+                canvas
+                    .fill_rect(row)
+                    .expect("Failed rendering controller row");
+
+                let ticked = settings
+                    .allowed_controllers
+                    .iter()
+                    .any(|allowed| allowed.matches(info));
+                let state = if !settings.is_controller_allowed(info) {
+                    "ignored".to_string()
+                } else {
+                    match held_buttons.get(which).map_or(0, Vec::len) {
+                        0 => "idle".to_string(),
+                        held => format!("{} held", held),
+                    }
+                };
+                let name: String = info.name.chars().take(22).collect();
+                draw_text(
+                    &mut canvas,
+                    &texture_creator,
+                    &small_font,
+                    &format!(
+                        "{} {} ({})",
+                        if ticked { "[x]" } else { "[ ]" },
+                        name,
+                        state
+                    ),
+                    row.x() + 6,
+                    row.y(),
+                );
+            }
+
+            // Draw the masher status panel
+            canvas.set_draw_color(Color::RGB(70, 87, 117));
+            canvas
+                .fill_rect(status_panel)
+                .expect("Failed rendering status panel");
+            for (index, line) in masher_status.lines().iter().enumerate() {
+                draw_text(
+                    &mut canvas,
+                    &texture_creator,
+                    &small_font,
+                    line,
+                    status_panel.x() + 6,
+                    status_panel.y() + 4 + index as i32 * status_line_height,
+                );
+            }
+
+            // Draw profile dropdown last so the open list covers the controls below it
+            canvas.set_draw_color(Color::RGB(70, 87, 117));
+            canvas
+                .fill_rect(profile_dropdown)
+                .expect("Failed rendering profile dropdown");
+            draw_text(
+                &mut canvas,
+                &texture_creator,
+                &small_font,
+                &format!("Profile: {}", settings.active_profile),
+                profile_dropdown.x() + 6,
+                profile_dropdown.y() + 1,
+            );
+            draw_text(
+                &mut canvas,
+                &texture_creator,
+                &small_font,
+                if profile_dropdown_open { "^" } else { "v" },
+                profile_dropdown.x() + profile_dropdown.width() as i32 - 16,
+                profile_dropdown.y() + 1,
+            );
+            if profile_dropdown_open {
+                let count = settings.profiles.len();
+                for (index, profile) in settings.profiles.iter().enumerate() {
+                    let option = profile_option(index, count);
+                    if profile.name == settings.active_profile {
+                        canvas.set_draw_color(Color::RGB(93, 114, 152));
+                    } else {
+                        canvas.set_draw_color(Color::RGB(70, 87, 117));
+                    }
+                    canvas
+                        .fill_rect(option)
+                        .expect("Failed rendering profile option");
+                    draw_text(
+                        &mut canvas,
+                        &texture_creator,
+                        &small_font,
+                        &profile.name,
+                        option.x() + 6,
+                        option.y() + 1,
+                    );
+                }
+            }
+
+            if let (Some(banner), Some(err)) = (error_banner, &settings_error) {
+                canvas.set_draw_color(Color::RGB(160, 62, 62));
+                canvas
+                    .fill_rect(banner)
+                    .expect("Failed rendering settings error");
+                draw_wrapped_text(
+                    &mut canvas,
+                    &texture_creator,
+                    &small_font,
+                    &format!("Settings were invalid and have been reset.\n{}", err),
+                    banner.x() + 8,
+                    banner.y() + 6,
+                    banner.width() as i32 - 16,
+                );
+            }
+
             canvas.present();
             new_input = false;
         }
@@ -0,0 +1,380 @@
+use crate::evdev_input::{EvdevChange, Forwarder};
+use crate::settings::{AxisThresholds, Input, ProxySettings, Settings};
+use crate::virtual_pad::{hold_only, PadResult, SharedPad, VirtualPad};
+use log::error;
+use sdl3::gamepad;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+#[derive(Default)]
+struct ProxyState {
+    /// Inputs held on each physical controller before remapping,
+    /// including triggers and stick directions pushed past their threshold
+    held: HashMap<u32, Vec<Input>>,
+    /// Where each controller's analog triggers are
+    triggers: HashMap<u32, Vec<(gamepad::Axis, f32)>>,
+    /// What the proxy is holding on the virtual controller
+    sent: Vec<Input>,
+    sent_triggers: Vec<(gamepad::Axis, f32)>,
+}
+
+/// Passes grabbed physical controllers through the virtual controller the masher uses,
+/// see `ProxySettings`
+pub struct Proxy {
+    pad: Mutex<SharedPad>,
+    settings: RwLock<(ProxySettings, AxisThresholds)>,
+    mashing_buttons: Arc<RwLock<Vec<Input>>>,
+    state: Mutex<ProxyState>,
+}
+
+impl Proxy {
+    pub fn new(
+        pad: SharedPad,
+        settings: &Settings,
+        mashing_buttons: Arc<RwLock<Vec<Input>>>,
+    ) -> Arc<Self> {
+        Arc::new(Proxy {
+            pad: Mutex::new(pad),
+            settings: RwLock::new((settings.proxy.clone(), settings.axis_thresholds)),
+            mashing_buttons,
+            state: Mutex::new(ProxyState::default()),
+        })
+    }
+
+    /// Passes the evdev gamepad reader's changes through the proxy
+    pub fn forwarder(self: &Arc<Self>) -> Forwarder {
+        let proxy = Arc::clone(self);
+        Arc::new(move |device_id, change| proxy.forward(device_id, change))
+    }
+
+    /// Picks up changed remaps, chord suppression and thresholds.
+    /// Whether the proxy is enabled is only read at startup.
+    pub fn apply(&self, settings: &Settings) {
+        *self
+            .settings
+            .write()
+            .expect("Failed to get state while storing proxy settings") =
+            (settings.proxy.clone(), settings.axis_thresholds);
+        let mut state = self.state.lock().expect("Failed to get proxy state");
+        if let Err(err) = self.send_held(&mut state) {
+            error!("Failed to update the virtual controller: {}", err);
+        }
+    }
+
+    /// Called by the masher as it starts and stops mashing
+    pub fn set_mashing(&self, mashing: bool) {
+        let (settings, _) = &*self
+            .settings
+            .read()
+            .expect("Failed to get state while reading proxy settings");
+        let pad = self.pad.lock().expect("Failed to get proxy state");
+        if let Err(err) = pad.set_masher_exclusive(mashing && settings.neutralize_while_mashing) {
+            error!("Failed to update the virtual controller: {}", err);
+        }
+    }
+
+    fn forward(&self, device_id: u32, change: &EvdevChange) {
+        if let Err(err) = self.try_forward(device_id, change) {
+            error!(
+                "Failed to pass input through the virtual controller: {}",
+                err
+            );
+        }
+    }
+
+    fn try_forward(&self, device_id: u32, change: &EvdevChange) -> PadResult {
+        let mut state = self.state.lock().expect("Failed to get proxy state");
+        match change {
+            EvdevChange::Button { input, pressed } => {
+                set_held(&mut state, device_id, *input, *pressed);
+            }
+            EvdevChange::Axis { axis, value } => {
+                let is_trigger = matches!(
+                    axis,
+                    gamepad::Axis::TriggerLeft | gamepad::Axis::TriggerRight
+                );
+                if !is_trigger {
+                    // Sticks go straight through, only their directions can be in a chord
+                    let mut pad = self.pad.lock().expect("Failed to get proxy state");
+                    pad.set_axis(*axis, *value)?;
+                    pad.sync()?;
+                } else {
+                    let triggers = state.triggers.entry(device_id).or_default();
+                    triggers.retain(|(moved, _)| moved != axis);
+                    triggers.push((*axis, *value));
+                }
+
+                let (_, thresholds) = &*self
+                    .settings
+                    .read()
+                    .expect("Failed to get state while reading proxy settings");
+                for positive in [false, true] {
+                    let Some(input) = Input::from_axis(*axis, positive) else {
+                        continue;
+                    };
+                    let Some(threshold) = thresholds.for_input(input) else {
+                        continue;
+                    };
+                    let travel = if positive { *value } else { -*value };
+                    let was_held = state
+                        .held
+                        .get(&device_id)
+                        .is_some_and(|held| held.contains(&input));
+                    set_held(
+                        &mut state,
+                        device_id,
+                        input,
+                        threshold.is_held(travel, was_held),
+                    );
+                }
+            }
+            EvdevChange::Connected { .. } => return Ok(()),
+            EvdevChange::Disconnected => {
+                state.held.remove(&device_id);
+                state.triggers.remove(&device_id);
+            }
+        }
+        self.send_held(&mut state)
+    }
+
+    /// Puts every controller's remapped inputs on the virtual controller.
+    /// Analog triggers go through as the furthest any controller is pulling them.
+    fn send_held(&self, state: &mut ProxyState) -> PadResult {
+        let (settings, _) = &*self
+            .settings
+            .read()
+            .expect("Failed to get state while reading proxy settings");
+        let chord = self
+            .mashing_buttons
+            .read()
+            .expect("Failed to get state while reading config");
+        let hidden = |device_id: &u32, input: &Input| {
+            settings.suppress_chord
+                && chord.contains(input)
+                && state
+                    .held
+                    .get(device_id)
+                    .is_some_and(|held| chord.iter().all(|button| held.contains(button)))
+        };
+
+        let mut inputs: Vec<Input> = Vec::new();
+        for (device_id, held) in &state.held {
+            let analog = state.triggers.get(device_id);
+            for input in held {
+                // Sticks and analog triggers are sent as axes
+                let is_axis = matches!(input, Input::Stick { .. })
+                    || input
+                        .trigger_axis()
+                        .zip(analog)
+                        .is_some_and(|(axis, triggers)| {
+                            triggers.iter().any(|(moved, _)| *moved == axis)
+                        });
+                if is_axis || hidden(device_id, input) {
+                    continue;
+                }
+
+                let output = settings.remapped(*input);
+                if !inputs.contains(&output) {
+                    inputs.push(output);
+                }
+            }
+        }
+
+        let mut trigger_values = vec![
+            (gamepad::Axis::TriggerLeft, 0.0),
+            (gamepad::Axis::TriggerRight, 0.0),
+        ];
+        for (device_id, triggers) in &state.triggers {
+            for (axis, value) in triggers {
+                let input = Input::from_axis(*axis, true).expect("triggers have a positive end");
+                if hidden(device_id, &input) {
+                    continue;
+                }
+                for (trigger, furthest) in trigger_values.iter_mut() {
+                    if trigger == axis && *value > *furthest {
+                        *furthest = *value;
+                    }
+                }
+            }
+        }
+
+        let mut pad = self.pad.lock().expect("Failed to get proxy state");
+        for (axis, value) in &trigger_values {
+            if !state.sent_triggers.contains(&(*axis, *value)) {
+                pad.set_axis(*axis, *value)?;
+            }
+        }
+        state.sent_triggers = trigger_values;
+        hold_only(&mut *pad, &mut state.sent, &inputs)
+    }
+}
+
+fn set_held(state: &mut ProxyState, device_id: u32, input: Input, pressed: bool) {
+    let held = state.held.entry(device_id).or_default();
+    held.retain(|held| *held != input);
+    if pressed {
+        held.push(input);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Remap;
+    use crate::virtual_pad::{PadEvent, PadSource, RecorderPad};
+
+    const DEVICE: u32 = 1;
+
+    fn button(button: gamepad::Button) -> Input {
+        Input::Button(button)
+    }
+
+    /// A proxy over a recorded virtual controller, along with the masher's handle to it.
+    /// The proxy's first round of centred triggers is left out of the recording.
+    fn recorded_proxy(settings: &Settings) -> (Arc<Proxy>, SharedPad, RecorderPad) {
+        let recorder = RecorderPad::default();
+        let masher = SharedPad::new(Box::new(recorder.clone()));
+        let proxy = Proxy::new(
+            masher.with_source(PadSource::Proxy),
+            settings,
+            Arc::new(RwLock::new(settings.active().mashing_triggers.clone())),
+        );
+        proxy.apply(settings);
+        recorder.clear();
+        (proxy, masher, recorder)
+    }
+
+    fn set_button(proxy: &Proxy, input: Input, pressed: bool) {
+        proxy.forward(DEVICE, &EvdevChange::Button { input, pressed });
+    }
+
+    #[test]
+    fn remapped_buttons_reach_the_game_as_their_target() {
+        let mut settings = Settings::default();
+        settings.proxy.remap = vec![Remap {
+            from: button(gamepad::Button::South),
+            to: button(gamepad::Button::East),
+        }];
+        let (proxy, _, recorder) = recorded_proxy(&settings);
+
+        set_button(&proxy, button(gamepad::Button::South), true);
+        set_button(&proxy, button(gamepad::Button::North), true);
+        set_button(&proxy, button(gamepad::Button::South), false);
+        assert_eq!(
+            recorder.events(),
+            [
+                PadEvent::Press(button(gamepad::Button::East)),
+                PadEvent::Sync,
+                PadEvent::Press(button(gamepad::Button::North)),
+                PadEvent::Sync,
+                PadEvent::Release(button(gamepad::Button::East)),
+                PadEvent::Sync,
+            ]
+        );
+    }
+
+    #[test]
+    fn suppress_chord_holds_back_the_chord_only_while_it_is_complete() {
+        let mut settings = Settings::default();
+        settings.proxy.suppress_chord = true;
+        let (proxy, _, recorder) = recorded_proxy(&settings);
+        let [first, second, last] = settings.active().mashing_triggers[..] else {
+            panic!("the default chord has three triggers");
+        };
+
+        set_button(&proxy, first, true);
+        set_button(&proxy, second, true);
+        recorder.clear();
+
+        set_button(&proxy, last, true);
+        assert_eq!(
+            recorder.events(),
+            [
+                PadEvent::Release(first),
+                PadEvent::Release(second),
+                PadEvent::Sync,
+            ]
+        );
+
+        recorder.clear();
+        set_button(&proxy, last, false);
+        assert_eq!(
+            recorder.events(),
+            [
+                PadEvent::Press(first),
+                PadEvent::Press(second),
+                PadEvent::Sync
+            ]
+        );
+    }
+
+    #[test]
+    fn chord_goes_through_without_suppress_chord() {
+        let settings = Settings::default();
+        let (proxy, _, recorder) = recorded_proxy(&settings);
+
+        for input in settings.active().mashing_triggers.clone() {
+            set_button(&proxy, input, true);
+        }
+        let pressed: Vec<Input> = recorder
+            .events()
+            .into_iter()
+            .filter_map(|event| match event {
+                PadEvent::Press(input) => Some(input),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pressed, settings.active().mashing_triggers);
+    }
+
+    #[test]
+    fn neutralize_while_mashing_lets_only_the_masher_through() {
+        let mut settings = Settings::default();
+        settings.proxy.neutralize_while_mashing = true;
+        let (proxy, mut masher, recorder) = recorded_proxy(&settings);
+        let north = button(gamepad::Button::North);
+        let south = button(gamepad::Button::South);
+        let west = button(gamepad::Button::West);
+
+        set_button(&proxy, north, true);
+        proxy.forward(
+            DEVICE,
+            &EvdevChange::Axis {
+                axis: gamepad::Axis::LeftX,
+                value: 0.5,
+            },
+        );
+        recorder.clear();
+
+        proxy.set_mashing(true);
+        let events = recorder.events();
+        assert!(events.contains(&PadEvent::Release(north)));
+        assert!(events.contains(&PadEvent::Axis(gamepad::Axis::LeftX, 0.0)));
+
+        // The physical controller is ignored, the masher still reaches the game
+        recorder.clear();
+        set_button(&proxy, west, true);
+        masher.press(south).unwrap();
+        masher.sync().unwrap();
+        assert!(!recorder.events().contains(&PadEvent::Press(west)));
+        assert!(recorder.events().contains(&PadEvent::Press(south)));
+
+        recorder.clear();
+        proxy.set_mashing(false);
+        let events = recorder.events();
+        assert!(events.contains(&PadEvent::Press(north)));
+        assert!(events.contains(&PadEvent::Press(west)));
+        assert!(events.contains(&PadEvent::Axis(gamepad::Axis::LeftX, 0.5)));
+    }
+
+    #[test]
+    fn set_mashing_leaves_the_proxy_alone_without_neutralize_while_mashing() {
+        let settings = Settings::default();
+        let (proxy, _, recorder) = recorded_proxy(&settings);
+
+        set_button(&proxy, button(gamepad::Button::North), true);
+        recorder.clear();
+        proxy.set_mashing(true);
+        assert!(recorder.events().is_empty());
+    }
+}
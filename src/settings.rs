@@ -0,0 +1,1366 @@
+use crate::text_masher::{
+    Activation, MashStep, MashTiming, MAX_AUTO_START_DELAY_MS, MAX_BUTTON_GAP_MS, MAX_MASH_RATE,
+    MAX_TOGGLE_TIMEOUT_SECS, MIN_DUTY_CYCLE, MIN_MASH_RATE,
+};
+use log::{error, info};
+use sdl3::gamepad;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the layout of the settings file changes, see `migrate`
+pub const SETTINGS_VERSION: u32 = 2;
+
+pub const DEFAULT_PROFILE_NAME: &str = "Default";
+
+/// How often `watch_settings` checks the settings file for changes
+const SETTINGS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Hashes of the files `save_settings` wrote that `watch_settings` hasn't seen yet,
+/// so HKCM's own saves aren't reloaded over newer changes made in the meantime
+static OWN_SAVES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// Saves that are never seen, e.g. overwritten before the next poll, are forgotten
+/// after this many more
+const MAX_OWN_SAVES: usize = 16;
+
+pub const DEFAULT_CHORD_LENGTH: usize = 3;
+pub const MAX_CHORD_LENGTH: usize = 8;
+
+fn default_chord_length() -> usize {
+    DEFAULT_CHORD_LENGTH
+}
+
+/// Why a settings file was rejected. `field` is the path to the offending value,
+/// e.g. `mash_sequence[1].buttons[0]`, when the problem can be pinned to one.
+#[derive(Debug)]
+pub struct SettingsError {
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl SettingsError {
+    fn new(message: impl Into<String>) -> Self {
+        SettingsError {
+            field: None,
+            message: message.into(),
+        }
+    }
+
+    fn at(field: impl Into<String>, message: impl Into<String>) -> Self {
+        SettingsError {
+            field: Some(field.into()),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{}: {}", field, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// A gamepad input or keyboard key, independent of the virtual device used to send it.
+///
+/// Inputs are stored in the settings file by name so the same file works on every platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Input {
+    Button(gamepad::Button),
+    LeftTrigger,
+    RightTrigger,
+    /// A keyboard key or mouse button, identified by its Linux input event code
+    Key(u16),
+    /// An analog stick pushed along `axis`, towards its positive end (right or down) or not
+    Stick {
+        axis: gamepad::Axis,
+        positive: bool,
+    },
+}
+
+static BUTTON_NAMES: &[(&str, gamepad::Button)] = &[
+    ("south", gamepad::Button::South),
+    ("east", gamepad::Button::East),
+    ("west", gamepad::Button::West),
+    ("north", gamepad::Button::North),
+    ("back", gamepad::Button::Back),
+    ("guide", gamepad::Button::Guide),
+    ("start", gamepad::Button::Start),
+    ("leftstick", gamepad::Button::LeftStick),
+    ("rightstick", gamepad::Button::RightStick),
+    ("leftshoulder", gamepad::Button::LeftShoulder),
+    ("rightshoulder", gamepad::Button::RightShoulder),
+    ("dpup", gamepad::Button::DPadUp),
+    ("dpdown", gamepad::Button::DPadDown),
+    ("dpleft", gamepad::Button::DPadLeft),
+    ("dpright", gamepad::Button::DPadRight),
+];
+
+static STICK_NAMES: &[(&str, gamepad::Axis, bool)] = &[
+    ("leftstick_up", gamepad::Axis::LeftY, false),
+    ("leftstick_down", gamepad::Axis::LeftY, true),
+    ("leftstick_left", gamepad::Axis::LeftX, false),
+    ("leftstick_right", gamepad::Axis::LeftX, true),
+    ("rightstick_up", gamepad::Axis::RightY, false),
+    ("rightstick_down", gamepad::Axis::RightY, true),
+    ("rightstick_left", gamepad::Axis::RightX, false),
+    ("rightstick_right", gamepad::Axis::RightX, true),
+];
+
+/// Keyboard keys, mouse buttons and their Linux input event codes,
+/// see `linux/input-event-codes.h`
+static KEY_NAMES: &[(&str, u16)] = &[
+    ("key_esc", 1),
+    ("key_1", 2),
+    ("key_2", 3),
+    ("key_3", 4),
+    ("key_4", 5),
+    ("key_5", 6),
+    ("key_6", 7),
+    ("key_7", 8),
+    ("key_8", 9),
+    ("key_9", 10),
+    ("key_0", 11),
+    ("key_minus", 12),
+    ("key_equal", 13),
+    ("key_backspace", 14),
+    ("key_tab", 15),
+    ("key_q", 16),
+    ("key_w", 17),
+    ("key_e", 18),
+    ("key_r", 19),
+    ("key_t", 20),
+    ("key_y", 21),
+    ("key_u", 22),
+    ("key_i", 23),
+    ("key_o", 24),
+    ("key_p", 25),
+    ("key_leftbrace", 26),
+    ("key_rightbrace", 27),
+    ("key_enter", 28),
+    ("key_leftctrl", 29),
+    ("key_a", 30),
+    ("key_s", 31),
+    ("key_d", 32),
+    ("key_f", 33),
+    ("key_g", 34),
+    ("key_h", 35),
+    ("key_j", 36),
+    ("key_k", 37),
+    ("key_l", 38),
+    ("key_semicolon", 39),
+    ("key_apostrophe", 40),
+    ("key_grave", 41),
+    ("key_leftshift", 42),
+    ("key_backslash", 43),
+    ("key_z", 44),
+    ("key_x", 45),
+    ("key_c", 46),
+    ("key_v", 47),
+    ("key_b", 48),
+    ("key_n", 49),
+    ("key_m", 50),
+    ("key_comma", 51),
+    ("key_dot", 52),
+    ("key_slash", 53),
+    ("key_rightshift", 54),
+    ("key_leftalt", 56),
+    ("key_space", 57),
+    ("key_capslock", 58),
+    ("key_f1", 59),
+    ("key_f2", 60),
+    ("key_f3", 61),
+    ("key_f4", 62),
+    ("key_f5", 63),
+    ("key_f6", 64),
+    ("key_f7", 65),
+    ("key_f8", 66),
+    ("key_f9", 67),
+    ("key_f10", 68),
+    ("key_kp7", 71),
+    ("key_kp8", 72),
+    ("key_kp9", 73),
+    ("key_kp4", 75),
+    ("key_kp5", 76),
+    ("key_kp6", 77),
+    ("key_kp1", 79),
+    ("key_kp2", 80),
+    ("key_kp3", 81),
+    ("key_kp0", 82),
+    ("key_f11", 87),
+    ("key_f12", 88),
+    ("key_kpenter", 96),
+    ("key_rightctrl", 97),
+    ("key_rightalt", 100),
+    ("key_home", 102),
+    ("key_up", 103),
+    ("key_pageup", 104),
+    ("key_left", 105),
+    ("key_right", 106),
+    ("key_end", 107),
+    ("key_down", 108),
+    ("key_pagedown", 109),
+    ("key_insert", 110),
+    ("key_delete", 111),
+    ("mouse_left", 0x110),
+    ("mouse_right", 0x111),
+    ("mouse_middle", 0x112),
+    ("mouse_side", 0x113),
+    ("mouse_extra", 0x114),
+];
+
+impl Input {
+    pub fn from_button(button: gamepad::Button) -> Option<Input> {
+        BUTTON_NAMES
+            .iter()
+            .any(|(_, b)| *b == button)
+            .then_some(Input::Button(button))
+    }
+
+    /// Returns the input for moving `axis` towards its positive or negative end.
+    /// Triggers only have a positive end.
+    pub fn from_axis(axis: gamepad::Axis, positive: bool) -> Option<Input> {
+        match axis {
+            gamepad::Axis::TriggerLeft => positive.then_some(Input::LeftTrigger),
+            gamepad::Axis::TriggerRight => positive.then_some(Input::RightTrigger),
+            _ => Some(Input::Stick { axis, positive }),
+        }
+    }
+
+    /// The analog axis behind a trigger
+    pub fn trigger_axis(self) -> Option<gamepad::Axis> {
+        match self {
+            Input::LeftTrigger => Some(gamepad::Axis::TriggerLeft),
+            Input::RightTrigger => Some(gamepad::Axis::TriggerRight),
+            _ => None,
+        }
+    }
+
+    /// Returns the key with this Linux input event code, if it has a name
+    pub fn from_key_code(code: u16) -> Option<Input> {
+        KEY_NAMES
+            .iter()
+            .any(|(_, c)| *c == code)
+            .then_some(Input::Key(code))
+    }
+
+    pub fn from_name(name: &str) -> Option<Input> {
+        match name {
+            "lefttrigger" => Some(Input::LeftTrigger),
+            "righttrigger" => Some(Input::RightTrigger),
+            _ => BUTTON_NAMES
+                .iter()
+                .find(|(button_name, _)| *button_name == name)
+                .map(|(_, button)| Input::Button(*button))
+                .or_else(|| {
+                    KEY_NAMES
+                        .iter()
+                        .find(|(key_name, _)| *key_name == name)
+                        .map(|(_, code)| Input::Key(*code))
+                })
+                .or_else(|| {
+                    STICK_NAMES
+                        .iter()
+                        .find(|(stick_name, _, _)| *stick_name == name)
+                        .map(|(_, axis, positive)| Input::Stick {
+                            axis: *axis,
+                            positive: *positive,
+                        })
+                }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Input::LeftTrigger => "lefttrigger",
+            Input::RightTrigger => "righttrigger",
+            Input::Button(button) => BUTTON_NAMES
+                .iter()
+                .find(|(_, b)| b == button)
+                .map(|(name, _)| *name)
+                .unwrap_or("unknown"),
+            Input::Key(code) => KEY_NAMES
+                .iter()
+                .find(|(_, c)| c == code)
+                .map(|(name, _)| *name)
+                .unwrap_or("unknown"),
+            Input::Stick { axis, positive } => STICK_NAMES
+                .iter()
+                .find(|(_, a, p)| a == axis && p == positive)
+                .map(|(name, _, _)| *name)
+                .unwrap_or("unknown"),
+        }
+    }
+}
+
+impl Serialize for Input {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Input {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        Input::from_name(&name)
+            .ok_or_else(|| de::Error::custom(format!("unknown button name {:?}", name)))
+    }
+}
+
+/// A named set of triggers and mash parameters, so different categories can keep their own
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub mashing_triggers: Vec<Input>,
+    #[serde(default)]
+    pub mash_timing: MashTiming,
+    #[serde(default)]
+    pub mash_sequence: Vec<MashStep<Input>>,
+    #[serde(default = "default_chord_length")]
+    pub chord_length: usize,
+    /// Holding all of these on one controller makes this the active profile.
+    /// Empty means the profile can only be picked from the GUI or command line.
+    #[serde(default)]
+    pub switch_chord: Vec<Input>,
+    #[serde(default)]
+    pub activation: Activation,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            name: DEFAULT_PROFILE_NAME.to_string(),
+            mashing_triggers: vec![
+                Input::Button(gamepad::Button::West),
+                Input::Button(gamepad::Button::South),
+                Input::Button(gamepad::Button::East),
+            ],
+            mash_timing: MashTiming::default(),
+            mash_sequence: Vec::new(),
+            chord_length: DEFAULT_CHORD_LENGTH,
+            switch_chord: Vec::new(),
+            activation: Activation::default(),
+        }
+    }
+}
+
+impl Profile {
+    /// Checks the values that deserialize fine but can't be used as they are.
+    /// `field` is the path of this profile in the settings file.
+    fn validate(&self, field: &str) -> Result<(), SettingsError> {
+        if self.name.trim().is_empty() {
+            return Err(SettingsError::at(
+                format!("{}.name", field),
+                "profile name can't be empty",
+            ));
+        }
+
+        check_duplicates(
+            &self.mashing_triggers,
+            &format!("{}.mashing_triggers", field),
+        )?;
+        if self.mashing_triggers.len() > MAX_CHORD_LENGTH {
+            return Err(SettingsError::at(
+                format!("{}.mashing_triggers", field),
+                format!("at most {} triggers are supported", MAX_CHORD_LENGTH),
+            ));
+        }
+        if !(1..=MAX_CHORD_LENGTH).contains(&self.chord_length) {
+            return Err(SettingsError::at(
+                format!("{}.chord_length", field),
+                format!("must be between 1 and {}", MAX_CHORD_LENGTH),
+            ));
+        }
+        if !self.mashing_triggers.is_empty() && self.mashing_triggers.len() != self.chord_length {
+            return Err(SettingsError::at(
+                format!("{}.chord_length", field),
+                format!(
+                    "must match the {} buttons in mashing_triggers",
+                    self.mashing_triggers.len()
+                ),
+            ));
+        }
+
+        let timing = &self.mash_timing;
+        if !(MIN_MASH_RATE..=MAX_MASH_RATE).contains(&timing.rate) {
+            return Err(SettingsError::at(
+                format!("{}.mash_timing.rate", field),
+                format!("must be between {} and {}", MIN_MASH_RATE, MAX_MASH_RATE),
+            ));
+        }
+        if !(MIN_DUTY_CYCLE..=1.0).contains(&timing.duty_cycle) {
+            return Err(SettingsError::at(
+                format!("{}.mash_timing.duty_cycle", field),
+                format!("must be between {} and 1", MIN_DUTY_CYCLE),
+            ));
+        }
+        if timing.button_gap_ms > MAX_BUTTON_GAP_MS {
+            return Err(SettingsError::at(
+                format!("{}.mash_timing.button_gap_ms", field),
+                format!("must be at most {}", MAX_BUTTON_GAP_MS),
+            ));
+        }
+
+        for (i, step) in self.mash_sequence.iter().enumerate() {
+            let step_field = format!("{}.mash_sequence[{}]", field, i);
+            if step.buttons.is_empty() {
+                return Err(SettingsError::at(
+                    format!("{}.buttons", step_field),
+                    "a step needs at least one button",
+                ));
+            }
+            check_duplicates(&step.buttons, &format!("{}.buttons", step_field))?;
+            if step.ticks == 0 {
+                return Err(SettingsError::at(
+                    format!("{}.ticks", step_field),
+                    "must be at least 1",
+                ));
+            }
+        }
+
+        if self.activation.toggle_timeout_secs > MAX_TOGGLE_TIMEOUT_SECS {
+            return Err(SettingsError::at(
+                format!("{}.activation.toggle_timeout_secs", field),
+                format!("must be at most {}", MAX_TOGGLE_TIMEOUT_SECS),
+            ));
+        }
+        if self.activation.auto_start_delay_ms > MAX_AUTO_START_DELAY_MS {
+            return Err(SettingsError::at(
+                format!("{}.activation.auto_start_delay_ms", field),
+                format!("must be at most {}", MAX_AUTO_START_DELAY_MS),
+            ));
+        }
+
+        check_duplicates(&self.switch_chord, &format!("{}.switch_chord", field))?;
+        if self.switch_chord.len() > MAX_CHORD_LENGTH {
+            return Err(SettingsError::at(
+                format!("{}.switch_chord", field),
+                format!("at most {} buttons are supported", MAX_CHORD_LENGTH),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Where an analog input counts as pressed and released, as a fraction of its full travel.
+/// Releasing at a lower point than pressing keeps an input resting near the line from flickering.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AxisThreshold {
+    pub press: f32,
+    pub release: f32,
+}
+
+impl AxisThreshold {
+    /// Whether an input at `travel` is held, given whether it was held before
+    pub fn is_held(&self, travel: f32, was_held: bool) -> bool {
+        if was_held {
+            travel > self.release
+        } else {
+            travel >= self.press
+        }
+    }
+
+    fn validate(&self, field: &str) -> Result<(), SettingsError> {
+        if !(self.press > 0.0 && self.press <= 1.0) {
+            return Err(SettingsError::at(
+                format!("{}.press", field),
+                "must be above 0 and at most 1",
+            ));
+        }
+        if !(self.release >= 0.0 && self.release <= self.press) {
+            return Err(SettingsError::at(
+                format!("{}.release", field),
+                "must be between 0 and the press threshold",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AxisThresholds {
+    pub left_trigger: AxisThreshold,
+    pub right_trigger: AxisThreshold,
+    /// Shared by every stick direction
+    pub sticks: AxisThreshold,
+}
+
+impl Default for AxisThresholds {
+    fn default() -> Self {
+        let trigger = AxisThreshold {
+            press: 0.3,
+            release: 0.2,
+        };
+        AxisThresholds {
+            left_trigger: trigger,
+            right_trigger: trigger,
+            sticks: AxisThreshold {
+                press: 0.8,
+                release: 0.7,
+            },
+        }
+    }
+}
+
+impl AxisThresholds {
+    /// The threshold for an analog input, or `None` for digital ones
+    pub fn for_input(&self, input: Input) -> Option<&AxisThreshold> {
+        match input {
+            Input::LeftTrigger => Some(&self.left_trigger),
+            Input::RightTrigger => Some(&self.right_trigger),
+            Input::Stick { .. } => Some(&self.sticks),
+            Input::Button(_) | Input::Key(_) => None,
+        }
+    }
+}
+
+/// What HKCM knows about a connected controller
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControllerInfo {
+    pub name: String,
+    /// SDL's GUID, or the bus, vendor, product and version ids when read through evdev
+    pub guid: Option<String>,
+    pub serial: Option<String>,
+}
+
+/// Picks out physical controllers. Every field that is set has to match.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ControllerMatch {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+}
+
+impl ControllerMatch {
+    /// Matches exactly this controller, as far as it can be told apart from others
+    pub fn from_info(info: &ControllerInfo) -> Self {
+        ControllerMatch {
+            name: Some(info.name.clone()),
+            guid: info.guid.clone(),
+            serial: info.serial.clone(),
+        }
+    }
+
+    pub fn matches(&self, info: &ControllerInfo) -> bool {
+        let field_matches = |wanted: &Option<String>, actual: Option<&String>| match wanted {
+            Some(wanted) => actual == Some(wanted),
+            None => true,
+        };
+        field_matches(&self.name, Some(&info.name))
+            && field_matches(&self.guid, info.guid.as_ref())
+            && field_matches(&self.serial, info.serial.as_ref())
+    }
+}
+
+/// Which controllers pool their held inputs, so one chord can be spread across them
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControllerMerge {
+    /// Every chord has to be held on a single controller
+    #[default]
+    None,
+    /// Every connected controller, plus the keyboard when keyboard triggers are on
+    All,
+    /// Only the controllers with these names
+    Selected(Vec<String>),
+}
+
+/// Sends `to` to the game in place of the physical button `from`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Remap {
+    pub from: Input,
+    pub to: Input,
+}
+
+/// Passing physical controllers through HKCM's virtual controller (Linux only).
+/// While enabled HKCM grabs every controller it reads, so the game only sees the virtual one.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxySettings {
+    /// Only read at startup, grabbing controllers can't be undone while HKCM runs
+    pub enabled: bool,
+    pub remap: Vec<Remap>,
+    /// Keep the trigger chord's buttons from the game while the whole chord is held
+    pub suppress_chord: bool,
+    /// Let go of everything the physical controllers hold while the masher is mashing,
+    /// so only its presses reach the game. This works through the proxy, so it does
+    /// nothing on Windows.
+    pub neutralize_while_mashing: bool,
+}
+
+impl ProxySettings {
+    /// What the game gets sent for a physical input
+    pub fn remapped(&self, input: Input) -> Input {
+        self.remap
+            .iter()
+            .find(|remap| remap.from == input)
+            .map_or(input, |remap| remap.to)
+    }
+
+    fn validate(&self, field: &str) -> Result<(), SettingsError> {
+        for (i, remap) in self.remap.iter().enumerate() {
+            let field = format!("{}.remap[{}]", field, i);
+            if !matches!(remap.from, Input::Button(_)) {
+                return Err(SettingsError::at(
+                    format!("{}.from", field),
+                    "only controller buttons can be remapped",
+                ));
+            }
+            if matches!(remap.to, Input::Stick { .. }) {
+                return Err(SettingsError::at(
+                    format!("{}.to", field),
+                    "stick directions can't be sent",
+                ));
+            }
+            if self.remap[..i].iter().any(|other| other.from == remap.from) {
+                return Err(SettingsError::at(
+                    format!("{}.from", field),
+                    format!("{:?} is already remapped", remap.from.name()),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub version: u32,
+    pub active_profile: String,
+    pub profiles: Vec<Profile>,
+    /// Read keyboards and mice directly so keys can be used in trigger chords (Linux only)
+    #[serde(default)]
+    pub keyboard_triggers: bool,
+    #[serde(default)]
+    pub axis_thresholds: AxisThresholds,
+    #[serde(default)]
+    pub merge_controllers: ControllerMerge,
+    /// Controllers that can trigger HKCM. Empty allows every controller.
+    #[serde(default)]
+    pub allowed_controllers: Vec<ControllerMatch>,
+    #[serde(default)]
+    pub proxy: ProxySettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            version: SETTINGS_VERSION,
+            active_profile: DEFAULT_PROFILE_NAME.to_string(),
+            profiles: vec![Profile::default()],
+            keyboard_triggers: false,
+            axis_thresholds: AxisThresholds::default(),
+            merge_controllers: ControllerMerge::None,
+            allowed_controllers: Vec::new(),
+            proxy: ProxySettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Whether `info`'s inputs count towards trigger and switch chords
+    pub fn is_controller_allowed(&self, info: &ControllerInfo) -> bool {
+        self.allowed_controllers.is_empty()
+            || self
+                .allowed_controllers
+                .iter()
+                .any(|allowed| allowed.matches(info))
+    }
+
+    /// Adds `info` to the allowed controllers, or removes it if it is already there
+    pub fn toggle_allowed_controller(&mut self, info: &ControllerInfo) {
+        let before = self.allowed_controllers.len();
+        self.allowed_controllers
+            .retain(|allowed| !allowed.matches(info));
+        if self.allowed_controllers.len() == before {
+            self.allowed_controllers
+                .push(ControllerMatch::from_info(info));
+        }
+    }
+
+    /// The profile currently in use. Validated settings always have one.
+    pub fn active(&self) -> &Profile {
+        self.profiles
+            .iter()
+            .find(|profile| profile.name == self.active_profile)
+            .unwrap_or(&self.profiles[0])
+    }
+
+    pub fn active_mut(&mut self) -> &mut Profile {
+        let index = self
+            .profiles
+            .iter()
+            .position(|profile| profile.name == self.active_profile)
+            .unwrap_or(0);
+        &mut self.profiles[index]
+    }
+
+    /// Makes `name` the active profile, returning false if there is no such profile
+    pub fn select_profile(&mut self, name: &str) -> bool {
+        if !self.profiles.iter().any(|profile| profile.name == name) {
+            return false;
+        }
+        self.active_profile = name.to_string();
+        true
+    }
+
+    /// Checks the values that deserialize fine but can't be used as they are
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if self.profiles.is_empty() {
+            return Err(SettingsError::at(
+                "profiles",
+                "at least one profile is required",
+            ));
+        }
+
+        for (i, profile) in self.profiles.iter().enumerate() {
+            let field = format!("profiles[{}]", i);
+            profile.validate(&field)?;
+
+            if self.profiles[..i].iter().any(|p| p.name == profile.name) {
+                return Err(SettingsError::at(
+                    format!("{}.name", field),
+                    format!("duplicate profile name {:?}", profile.name),
+                ));
+            }
+        }
+
+        // Switch chords are watched whichever profile is active, and one that is part of a
+        // trigger chord, or contains one, would switch profiles and start mashing together
+        for (i, profile) in self.profiles.iter().enumerate() {
+            let switch_chord = &profile.switch_chord;
+            if switch_chord.is_empty() {
+                continue;
+            }
+            for other in &self.profiles {
+                let triggers = &other.mashing_triggers;
+                if triggers.is_empty() {
+                    continue;
+                }
+                if switch_chord.iter().all(|input| triggers.contains(input))
+                    || triggers.iter().all(|input| switch_chord.contains(input))
+                {
+                    return Err(SettingsError::at(
+                        format!("profiles[{}].switch_chord", i),
+                        format!(
+                            "can't be part of or contain the mashing triggers of profile {:?}",
+                            other.name
+                        ),
+                    ));
+                }
+            }
+        }
+
+        self.axis_thresholds
+            .left_trigger
+            .validate("axis_thresholds.left_trigger")?;
+        self.axis_thresholds
+            .right_trigger
+            .validate("axis_thresholds.right_trigger")?;
+        self.axis_thresholds
+            .sticks
+            .validate("axis_thresholds.sticks")?;
+
+        if let ControllerMerge::Selected(names) = &self.merge_controllers {
+            if names.is_empty() {
+                return Err(SettingsError::at(
+                    "merge_controllers.selected",
+                    "list at least one controller name",
+                ));
+            }
+        }
+
+        for (i, allowed) in self.allowed_controllers.iter().enumerate() {
+            if *allowed == ControllerMatch::default() {
+                return Err(SettingsError::at(
+                    format!("allowed_controllers[{}]", i),
+                    "set at least one of name, guid or serial",
+                ));
+            }
+        }
+
+        self.proxy.validate("proxy")?;
+
+        if !self
+            .profiles
+            .iter()
+            .any(|profile| profile.name == self.active_profile)
+        {
+            return Err(SettingsError::at(
+                "active_profile",
+                format!("no profile named {:?}", self.active_profile),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn check_duplicates(inputs: &[Input], field: &str) -> Result<(), SettingsError> {
+    for (i, input) in inputs.iter().enumerate() {
+        if inputs[..i].contains(input) {
+            return Err(SettingsError::at(
+                format!("{}[{}]", field, i),
+                format!("duplicate button {:?}", input.name()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Button codes written by the Linux build before settings were versioned
+static LEGACY_UINPUT_CODES: &[(i64, &str)] = &[
+    (0x130, "south"),
+    (0x131, "east"),
+    (0x133, "north"),
+    (0x134, "west"),
+    (0x136, "leftshoulder"),
+    (0x137, "rightshoulder"),
+    (0x138, "lefttrigger"),
+    (0x139, "righttrigger"),
+    (0x13A, "back"),
+    (0x13B, "start"),
+    (0x13C, "guide"),
+    (0x13D, "leftstick"),
+    (0x13E, "rightstick"),
+];
+
+/// `XButtons` bits written by the Windows build before settings were versioned
+static LEGACY_XBUTTONS: &[(i64, &str)] = &[
+    (0x0001, "dpup"),
+    (0x0002, "dpdown"),
+    (0x0004, "dpleft"),
+    (0x0008, "dpright"),
+    (0x0010, "start"),
+    (0x0020, "back"),
+    (0x0040, "leftstick"),
+    (0x0080, "rightstick"),
+    (0x0100, "leftshoulder"),
+    (0x0200, "rightshoulder"),
+    (0x0400, "guide"),
+    (0x1000, "south"),
+    (0x2000, "east"),
+    (0x4000, "west"),
+    (0x8000, "north"),
+];
+
+/// Converts a single input from either legacy format into its name
+fn migrate_legacy_input(value: &Value) -> Result<Value, String> {
+    let name = match value {
+        // Linux stored raw uinput codes
+        Value::Number(code) => LEGACY_UINPUT_CODES
+            .iter()
+            .find(|(c, _)| Some(*c) == code.as_i64())
+            .map(|(_, name)| *name)
+            .ok_or_else(|| format!("unknown button code {}", code))?,
+        // Windows stored the VigemInput enum
+        Value::String(variant) if variant == "LeftTrigger" => "lefttrigger",
+        Value::String(variant) if variant == "RightTrigger" => "righttrigger",
+        Value::Object(map) if map.contains_key("Button") => {
+            let bits = &map["Button"];
+            LEGACY_XBUTTONS
+                .iter()
+                .find(|(b, _)| Some(*b) == bits.as_i64())
+                .map(|(_, name)| *name)
+                .ok_or_else(|| format!("unknown button code {}", bits))?
+        }
+        _ => return Err(format!("unrecognised button {}", value)),
+    };
+    Ok(Value::String(name.to_string()))
+}
+
+fn migrate_legacy_inputs(value: &mut Value, field: &str) -> Result<(), SettingsError> {
+    if let Some(inputs) = value.as_array_mut() {
+        for (i, input) in inputs.iter_mut().enumerate() {
+            *input = migrate_legacy_input(input)
+                .map_err(|message| SettingsError::at(format!("{}[{}]", field, i), message))?;
+        }
+    }
+    Ok(())
+}
+
+fn settings_version(value: &Value) -> Result<u64, SettingsError> {
+    match value.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| SettingsError::at("version", format!("invalid version {}", version))),
+    }
+}
+
+/// Upgrades settings written by older versions of HKCM to the current layout
+fn migrate(mut value: Value) -> Result<Value, SettingsError> {
+    let version = settings_version(&value)?;
+
+    if version > SETTINGS_VERSION as u64 {
+        return Err(SettingsError::at(
+            "version",
+            format!(
+                "settings version {} is newer than this version of HKCM supports ({})",
+                version, SETTINGS_VERSION
+            ),
+        ));
+    }
+
+    if version < 1 {
+        info!("Migrating settings from the platform specific format");
+        if let Some(triggers) = value.get_mut("mashing_triggers") {
+            migrate_legacy_inputs(triggers, "mashing_triggers")?;
+        }
+        if let Some(steps) = value
+            .get_mut("mash_sequence")
+            .and_then(|sequence| sequence.as_array_mut())
+        {
+            for (i, step) in steps.iter_mut().enumerate() {
+                if let Some(buttons) = step.get_mut("buttons") {
+                    migrate_legacy_inputs(buttons, &format!("mash_sequence[{}].buttons", i))?;
+                }
+            }
+        }
+    }
+
+    if version < 2 {
+        // Version 1 had a single set of triggers, which becomes the default profile
+        if let Some(map) = value.as_object_mut() {
+            let mut profile = serde_json::Map::new();
+            profile.insert("name".to_string(), Value::from(DEFAULT_PROFILE_NAME));
+            for key in [
+                "mashing_triggers",
+                "mash_timing",
+                "mash_sequence",
+                "chord_length",
+            ] {
+                if let Some(field) = map.remove(key) {
+                    profile.insert(key.to_string(), field);
+                }
+            }
+            map.insert(
+                "active_profile".to_string(),
+                Value::from(DEFAULT_PROFILE_NAME),
+            );
+            map.insert(
+                "profiles".to_string(),
+                Value::Array(vec![Value::Object(profile)]),
+            );
+        }
+    }
+
+    if let Some(map) = value.as_object_mut() {
+        map.insert("version".to_string(), Value::from(SETTINGS_VERSION));
+    }
+    Ok(value)
+}
+
+/// Parses and validates a settings file, returning the settings along with the version
+/// the file was written in
+fn parse_versioned_settings(json: &str) -> Result<(Settings, u64), SettingsError> {
+    let value: Value = serde_json::from_str(json).map_err(|e| SettingsError::new(e.to_string()))?;
+    let file_version = settings_version(&value)?;
+    let value = migrate(value)?;
+    let settings: Settings = serde_path_to_error::deserialize(value).map_err(|e| {
+        let field = e.path().to_string();
+        let message = e.into_inner().to_string();
+        if field == "." {
+            SettingsError::new(message)
+        } else {
+            SettingsError::at(field, message)
+        }
+    })?;
+    settings.validate()?;
+    Ok((settings, file_version))
+}
+
+/// Picks a backup name next to the settings file that won't overwrite an earlier backup
+fn backup_path(settings_path: &Path) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let mut file_name = settings_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}.bak", timestamp));
+    settings_path.with_file_name(file_name)
+}
+
+/// Reads the settings file, creating it with the default settings if it doesn't exist yet.
+///
+/// A file that can't be used is backed up and replaced with the defaults, and the
+/// reason is returned alongside them so it can be shown to the user.
+pub fn load_settings(settings_path: &Path) -> (Settings, Option<SettingsError>) {
+    if !settings_path.exists() {
+        let default_config = Settings::default();
+        save_settings(&default_config, settings_path);
+
+        return (default_config, None);
+    }
+
+    let parsed = std::fs::read_to_string(settings_path)
+        .map_err(|e| SettingsError::new(format!("failed to read settings file: {}", e)))
+        .and_then(|json| parse_versioned_settings(&json));
+
+    match parsed {
+        Ok((settings, file_version)) => {
+            if file_version < SETTINGS_VERSION as u64 {
+                save_settings(&settings, settings_path);
+                info!("Saved migrated settings to {}", settings_path.display());
+            }
+            (settings, None)
+        }
+        Err(err) => {
+            error!("Invalid settings in {}: {}", settings_path.display(), err);
+
+            let backup = backup_path(settings_path);
+            if let Err(e) = std::fs::copy(settings_path, &backup) {
+                // Keep the broken file around rather than losing it to the defaults
+                error!("Failed to back up settings to {}: {}", backup.display(), e);
+                return (Settings::default(), Some(err));
+            }
+            info!("Backed up invalid settings to {}", backup.display());
+
+            let default_config = Settings::default();
+            save_settings(&default_config, settings_path);
+            (default_config, Some(err))
+        }
+    }
+}
+
+/// Reads and validates the settings file without changing it
+pub fn read_settings(settings_path: &Path) -> Result<Settings, SettingsError> {
+    let json = std::fs::read_to_string(settings_path)
+        .map_err(|e| SettingsError::new(format!("failed to read settings file: {}", e)))?;
+    parse_versioned_settings(&json).map(|(settings, _)| settings)
+}
+
+fn content_hash(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Forgets `contents` if `save_settings` wrote them, returning whether it did
+fn take_own_save(contents: &[u8]) -> bool {
+    let hash = content_hash(contents);
+    let mut own_saves = OWN_SAVES.lock().expect("Failed to get recent saves");
+    match own_saves.iter().position(|saved| *saved == hash) {
+        Some(index) => {
+            own_saves.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+fn record_own_save(contents: &[u8]) {
+    let mut own_saves = OWN_SAVES.lock().expect("Failed to get recent saves");
+    if own_saves.len() == MAX_OWN_SAVES {
+        own_saves.remove(0);
+    }
+    own_saves.push(content_hash(contents));
+}
+
+/// The modification time and size of the settings file, either changes when it's written
+fn file_stamp(settings_path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(settings_path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Polls the settings file and sends its contents every time it changes on disk.
+///
+/// Edits that don't parse or validate are logged and skipped, so whoever receives the
+/// settings keeps the last good version. Files written by `save_settings` are skipped
+/// as well, the settings they hold are already in use.
+pub fn watch_settings(settings_path: PathBuf) -> Receiver<Settings> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut last_stamp = file_stamp(&settings_path);
+        loop {
+            thread::sleep(SETTINGS_POLL_INTERVAL);
+
+            let stamp = file_stamp(&settings_path);
+            if stamp.is_none() || stamp == last_stamp {
+                continue;
+            }
+            last_stamp = stamp;
+
+            match std::fs::read(&settings_path) {
+                Ok(contents) if take_own_save(&contents) => continue,
+                Ok(_) => {}
+                Err(err) => {
+                    error!("Failed to read {}: {}", settings_path.display(), err);
+                    continue;
+                }
+            }
+
+            match read_settings(&settings_path) {
+                Ok(settings) => {
+                    if sender.send(settings).is_err() {
+                        // Nobody is listening any more
+                        return;
+                    }
+                }
+                Err(err) => error!(
+                    "Ignoring invalid settings in {}: {}",
+                    settings_path.display(),
+                    err
+                ),
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Writes the settings next to `settings_path` first and then renames them over it,
+/// so `watch_settings` never reads a half written file
+pub fn save_settings(settings: &Settings, settings_path: &Path) {
+    let json = serde_json::to_string_pretty(settings).expect("Failed to convert config to json");
+    let mut temp_name = settings_path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = settings_path.with_file_name(temp_name);
+
+    if let Some(parent) = settings_path.parent() {
+        std::fs::create_dir_all(parent).expect("Failed to create settings directory");
+    }
+    let mut file = File::create(&temp_path).expect("Failed to create settings file");
+    file.write_all(json.as_bytes())
+        .expect("Failed to write config to file");
+    drop(file);
+    record_own_save(json.as_bytes());
+    std::fs::rename(&temp_path, settings_path).expect("Failed to replace settings file");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A settings path in a directory of its own under the system temp dir
+    fn temp_settings_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hkcm-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("HKCM_settings.json")
+    }
+
+    fn button(button: gamepad::Button) -> Input {
+        Input::Button(button)
+    }
+
+    #[test]
+    fn legacy_linux_and_windows_files_migrate_to_the_same_settings() {
+        // uinput codes for BTN_WEST, BTN_SOUTH and BTN_TL2
+        let linux = r#"{"mashing_triggers": [308, 304, 312]}"#;
+        let windows =
+            r#"{"mashing_triggers": [{"Button": 16384}, {"Button": 4096}, "LeftTrigger"]}"#;
+
+        let (linux, linux_version) = parse_versioned_settings(linux).unwrap();
+        let (windows, windows_version) = parse_versioned_settings(windows).unwrap();
+        assert_eq!((linux_version, windows_version), (0, 0));
+        assert!(linux == windows);
+
+        assert_eq!(linux.version, SETTINGS_VERSION);
+        assert_eq!(linux.active_profile, DEFAULT_PROFILE_NAME);
+        assert_eq!(linux.profiles.len(), 1);
+        let profile = linux.active();
+        assert_eq!(profile.name, DEFAULT_PROFILE_NAME);
+        assert_eq!(
+            profile.mashing_triggers,
+            [
+                button(gamepad::Button::West),
+                button(gamepad::Button::South),
+                Input::LeftTrigger
+            ]
+        );
+        assert!(profile.mash_timing == MashTiming::default());
+    }
+
+    #[test]
+    fn legacy_file_with_an_unknown_button_reports_where() {
+        let err = parse_versioned_settings(r#"{"mashing_triggers": [304, 999]}"#)
+            .err()
+            .unwrap();
+        assert_eq!(err.field.as_deref(), Some("mashing_triggers[1]"));
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let speedrun = Profile {
+            name: "Any%".to_string(),
+            mashing_triggers: vec![button(gamepad::Button::North)],
+            mash_timing: MashTiming {
+                rate: 20.0,
+                duty_cycle: 0.5,
+                button_gap_ms: 10,
+            },
+            mash_sequence: vec![MashStep {
+                buttons: vec![button(gamepad::Button::South), Input::RightTrigger],
+                ticks: 2,
+            }],
+            chord_length: 1,
+            switch_chord: vec![
+                button(gamepad::Button::Back),
+                button(gamepad::Button::DPadUp),
+            ],
+            activation: Activation::default(),
+        };
+        let settings = Settings {
+            active_profile: speedrun.name.clone(),
+            profiles: vec![Profile::default(), speedrun],
+            keyboard_triggers: true,
+            ..Settings::default()
+        };
+
+        let json = serde_json::to_string_pretty(&settings).unwrap();
+        let (parsed, version) = parse_versioned_settings(&json).unwrap();
+        assert_eq!(version, SETTINGS_VERSION as u64);
+        assert!(parsed == settings);
+    }
+
+    /// The default settings as JSON, for tests to break one field of
+    fn default_json() -> Value {
+        serde_json::to_value(Settings::default()).unwrap()
+    }
+
+    fn error_field(json: Value) -> Option<String> {
+        parse_versioned_settings(&json.to_string())
+            .err()
+            .expect("settings should be rejected")
+            .field
+    }
+
+    #[test]
+    fn duplicate_trigger_reports_its_index() {
+        let mut json = default_json();
+        json["profiles"][0]["mashing_triggers"] = serde_json::json!(["west", "south", "west"]);
+        assert_eq!(
+            error_field(json).as_deref(),
+            Some("profiles[0].mashing_triggers[2]")
+        );
+    }
+
+    #[test]
+    fn unknown_button_name_reports_its_index() {
+        let mut json = default_json();
+        json["profiles"][0]["mash_sequence"] = serde_json::json!([{"buttons": ["south", "jump"]}]);
+        assert_eq!(
+            error_field(json).as_deref(),
+            Some("profiles[0].mash_sequence[0].buttons[1]")
+        );
+    }
+
+    #[test]
+    fn out_of_range_timing_reports_the_field() {
+        let mut json = default_json();
+        json["profiles"][0]["mash_timing"]["rate"] = serde_json::json!(MAX_MASH_RATE + 1.0);
+        assert_eq!(
+            error_field(json).as_deref(),
+            Some("profiles[0].mash_timing.rate")
+        );
+
+        let mut json = default_json();
+        json["profiles"][0]["mash_timing"]["duty_cycle"] = serde_json::json!(0.0);
+        assert_eq!(
+            error_field(json).as_deref(),
+            Some("profiles[0].mash_timing.duty_cycle")
+        );
+
+        let mut json = default_json();
+        json["profiles"][0]["mash_timing"]["button_gap_ms"] =
+            serde_json::json!(MAX_BUTTON_GAP_MS + 1);
+        assert_eq!(
+            error_field(json).as_deref(),
+            Some("profiles[0].mash_timing.button_gap_ms")
+        );
+    }
+
+    #[test]
+    fn chord_length_has_to_match_the_triggers() {
+        let mut json = default_json();
+        json["profiles"][0]["chord_length"] = serde_json::json!(2);
+        assert_eq!(
+            error_field(json).as_deref(),
+            Some("profiles[0].chord_length")
+        );
+    }
+
+    #[test]
+    fn switch_chord_overlapping_a_trigger_chord_is_rejected() {
+        // The default triggers are west, south and east
+        for switch_chord in [
+            serde_json::json!(["west", "south", "east"]),
+            serde_json::json!(["south", "east"]),
+            serde_json::json!(["west", "south", "east", "back"]),
+        ] {
+            let mut json = default_json();
+            let mut other = json["profiles"][0].clone();
+            other["name"] = serde_json::json!("Other");
+            other["switch_chord"] = switch_chord;
+            json["profiles"] = serde_json::json!([json["profiles"][0], other]);
+            assert_eq!(
+                error_field(json).as_deref(),
+                Some("profiles[1].switch_chord")
+            );
+        }
+
+        let mut json = default_json();
+        json["profiles"][0]["switch_chord"] = serde_json::json!(["south", "back"]);
+        assert!(parse_versioned_settings(&json.to_string()).is_ok());
+    }
+
+    #[test]
+    fn save_creates_missing_directories() {
+        let dir = temp_settings_path("save").with_file_name("missing");
+        let settings_path = dir.join("nested").join("HKCM_settings.json");
+        save_settings(&Settings::default(), &settings_path);
+        assert!(read_settings(&settings_path).unwrap() == Settings::default());
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn watcher_skips_own_saves_but_not_other_edits() {
+        let settings_path = temp_settings_path("watch");
+        save_settings(&Settings::default(), &settings_path);
+        let updates = watch_settings(settings_path.clone());
+
+        let mut saved = Settings::default();
+        saved.profiles[0].mash_timing.rate = 20.0;
+        save_settings(&saved, &settings_path);
+        assert!(updates.recv_timeout(SETTINGS_POLL_INTERVAL * 3).is_err());
+
+        let edited = Settings {
+            keyboard_triggers: true,
+            ..Settings::default()
+        };
+        std::fs::write(
+            &settings_path,
+            serde_json::to_string_pretty(&edited).unwrap(),
+        )
+        .unwrap();
+        let reloaded = updates.recv_timeout(SETTINGS_POLL_INTERVAL * 3).unwrap();
+        assert!(reloaded == edited);
+
+        std::fs::remove_dir_all(settings_path.parent().unwrap()).unwrap();
+    }
+}
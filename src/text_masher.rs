@@ -1,225 +1,909 @@
-use asr::{
-    game_engine::unity::mono::{self, UnityPointer},
-    Address, PointerSize, Process,
-};
-use once_cell::sync::Lazy;
+use crate::hollow_knight::GameReader;
+use crate::settings::Input;
+use crate::virtual_pad::{hold_only, VirtualPad};
+use serde::{Deserialize, Serialize};
 use std::{
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
     },
     time::Instant,
 };
 use std::{thread::sleep, time::Duration};
 
-pub const MAX_MASHING_KEY_COUNT: u8 = 3;
-
-const TARGET_RATE: f64 = 37.0;
-pub static IS_MASHER_ACTIVE: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
-pub static SHOULD_TERMINATE_MASHER: Lazy<Arc<AtomicBool>> =
-    Lazy::new(|| Arc::new(AtomicBool::new(false)));
-
-struct HKConfig {
-    module_name: &'static str,
-    base_offset: u32,
-    pointer_chain: &'static [u32],
-}
-
-static CONFIGS: &[(&str, HKConfig)] = &[
-    (
-        "hollow_knight.x86_64",
-        HKConfig {
-            module_name: "libmono.so",
-            base_offset: 0x004AAA68,
-            pointer_chain: &[0x138, 0x898, 0x20, 0x28, 0x10c],
-        },
-    ),
-    (
-        "Hollow Knight.exe",
-        HKConfig {
-            module_name: "Hollow Knight.exe",
-            base_offset: 0x00FB85AC,
-            pointer_chain: &[0x20, 0x4, 0x10, 0x4, 0x4, 0x50, 0x38, 0x0],
-        },
-    ),
-    (
-        "hollow_knight.exe",
-        HKConfig {
-            module_name: "hollow_knight.exe",
-            base_offset: 0x00FB85AC,
-            pointer_chain: &[0x20, 0x4, 0x10, 0x4, 0x4, 0x50, 0x38, 0x0],
-        },
-    ),
-];
-
-fn attach_hollow_knight() -> Option<(Process, &'static str)> {
-    CONFIGS
-        .iter()
-        .find_map(|(name, _config)| Process::attach(name).map(|proc| (proc, *name)))
+pub const DEFAULT_MASH_RATE: f64 = 37.0;
+pub const MIN_MASH_RATE: f64 = 1.0;
+pub const MAX_MASH_RATE: f64 = 120.0;
+pub const MIN_DUTY_CYCLE: f64 = 0.1;
+pub const MAX_BUTTON_GAP_MS: u64 = 500;
+pub const MAX_TOGGLE_TIMEOUT_SECS: u64 = 600;
+pub const MAX_AUTO_START_DELAY_MS: u64 = 5000;
+
+/// Share of a step that buttons the next step presses again are always released for,
+/// so the game sees a new press even at a full duty cycle
+const MIN_REPEAT_RELEASE: f64 = 0.1;
+
+/// Controls how quickly the masher cycles through the mashing buttons.
+///
+/// Every button press lasts `1 / rate` seconds. The button is held for `duty_cycle`
+/// of that interval and released for the rest, then the masher waits an extra
+/// `button_gap_ms` before pressing the next button. At a full duty cycle the button
+/// stays down until the next button is pressed, unless the next step presses the same
+/// button, which is still released for the last `MIN_REPEAT_RELEASE` of the interval.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MashTiming {
+    pub rate: f64,
+    pub duty_cycle: f64,
+    pub button_gap_ms: u64,
 }
 
-fn get_config(process_name: &str) -> Option<&'static HKConfig> {
-    CONFIGS.iter().find_map(|(name, config)| {
-        if *name == process_name {
-            Some(config)
-        } else {
-            None
+impl Default for MashTiming {
+    fn default() -> Self {
+        MashTiming {
+            rate: DEFAULT_MASH_RATE,
+            duty_cycle: 1.0,
+            button_gap_ms: 0,
         }
-    })
+    }
 }
 
-fn wait_attach_until_close<F, R>(mut f: F)
-where
-    F: FnMut(&Process, &mono::Module, &mono::Image, &HKConfig) -> R,
-{
-    let process_opt = attach_hollow_knight();
-    if let None = process_opt {
-        sleep(std::time::Duration::from_millis(500));
-        return;
+impl MashTiming {
+    fn press_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.rate)
     }
+}
 
-    let (process, process_name) = process_opt.unwrap();
-    log::info!("Found Hollow Knight: {:?}", process_name);
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivationMode {
+    /// Mash only while the trigger chord is held
+    #[default]
+    Hold,
+    /// Pressing the trigger chord arms the masher until a dialogue has been mashed
+    /// through or the chord is pressed again
+    Toggle,
+    /// Mash every dialogue as soon as it accepts input, without any chord
+    Auto,
+}
 
-    let config = match get_config(process_name) {
-        Some(cfg) => cfg,
-        None => {
-            log::info!("No config found for {:?}", process_name);
-            return;
-        }
-    };
+/// Controls how the trigger chord turns the masher on and off
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Activation {
+    pub mode: ActivationMode,
+    /// In toggle mode, disarm this many seconds after arming. 0 never disarms on its own.
+    pub toggle_timeout_secs: u64,
+    /// In auto mode, how long a dialogue has to be open before mashing starts
+    pub auto_start_delay_ms: u64,
+    /// In auto mode, the conversations that may be mashed, by the key the game shows them
+    /// by such as `ELDERBUG_INTRO_MAIN`. A trailing `*` matches every key starting with the
+    /// rest, e.g. `SLY_*`. Empty allows every conversation.
+    pub auto_conversations: Vec<String>,
+}
 
-    let mut found_module = false;
-    loop {
-        if let Some(module) = mono::Module::attach_auto_detect(&process) {
-            if !found_module {
-                found_module = true;
-                log::info!("GameManagerFinder wait_attach: module get_default_image...");
-            }
-            for _ in 0..0x10 {
-                if let Some(image) = module.get_default_image(&process) {
-                    log::info!("GameManagerFinder wait_attach: got module and image");
-                    f(&process, &module, &image, &config);
-                }
-            }
-            break;
-        }
-        log::info!("GameManagerFinder wait_attach: waiting for attach...");
-        std::thread::sleep(std::time::Duration::from_millis(250));
+impl Activation {
+    fn allows_conversation(&self, key: Option<&str>) -> bool {
+        self.auto_conversations.is_empty()
+            || key.is_some_and(|key| {
+                self.auto_conversations
+                    .iter()
+                    .any(|allowed| match allowed.strip_suffix('*') {
+                        Some(prefix) => key.starts_with(prefix),
+                        None => allowed == key,
+                    })
+            })
     }
 }
 
-fn resolve_pointer_chain(
-    process: &Process,
-    base: Address,
-    chain: &[u32],
-    ps: PointerSize,
-) -> Option<Address> {
-    if chain.is_empty() {
-        return Some(base);
+/// One step of a mash sequence. Every button in `buttons` is held down together
+/// for `ticks` press intervals before the masher moves on to the next step.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MashStep<B> {
+    pub buttons: Vec<B>,
+    #[serde(default = "default_step_ticks")]
+    pub ticks: u32,
+}
+
+fn default_step_ticks() -> u32 {
+    1
+}
+
+/// Returns the sequence the masher should run. An empty `sequence` falls back to
+/// pressing each of the `triggers` one at a time, in order.
+pub fn resolve_mash_sequence<B: Clone>(
+    sequence: &[MashStep<B>],
+    triggers: &[B],
+) -> Vec<MashStep<B>> {
+    if !sequence.is_empty() {
+        return sequence.to_vec();
     }
 
-    let mut addr = process.read_pointer(base, ps).ok()?;
-    for &offset in &chain[..chain.len() - 1] {
-        addr = process.read_pointer(addr + offset, ps).ok()?;
+    triggers
+        .iter()
+        .map(|button| MashStep {
+            buttons: vec![button.clone()],
+            ticks: default_step_ticks(),
+        })
+        .collect()
+}
+
+fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now {
+        sleep(deadline - now);
     }
+}
 
-    Some(addr + chain[chain.len() - 1])
+fn release_all(pad: &mut dyn VirtualPad, held: &mut Vec<Input>) {
+    held.clear();
+    if let Err(err) = pad.reset() {
+        log::error!("Failed to release virtual controller buttons: {}", err);
+    }
 }
 
-pub fn text_masher(
-    mut do_key_event: impl FnMut(u8),
-    toggle_overlay: impl Fn(bool) -> Result<(), Box<dyn std::error::Error>>,
+/// The overlay is optional, so failing to reach it is only logged and never stops the masher
+fn show_overlay(
+    toggle_overlay: &impl Fn(bool) -> Result<(), Box<dyn std::error::Error>>,
+    shown: bool,
 ) {
-    log::info!("TextMasher starting up");
-    let target_interval: Duration = Duration::from_secs_f64(1.0 / TARGET_RATE);
-    IS_MASHER_ACTIVE.store(false, Ordering::SeqCst);
-    SHOULD_TERMINATE_MASHER.store(false, Ordering::SeqCst);
+    if let Err(err) = toggle_overlay(shown) {
+        log::error!("Failed to toggle masher overlay: {}", err);
+    }
+}
 
-    'mainloop: loop {
-        if SHOULD_TERMINATE_MASHER.load(Ordering::SeqCst) {
-            let _ = toggle_overlay(false);
-            break 'mainloop;
-        }
+/// Tells a running `Masher` what to do
+#[derive(Clone, Debug)]
+pub enum MasherCommand {
+    /// Arms the masher, as if the trigger chord were held
+    Activate,
+    Deactivate,
+    /// Switches to another profile's settings, taking effect from the next step
+    ReloadConfig(MasherConfig),
+    Shutdown,
+}
 
-        log::info!("GameManagerFinder wait_attach...");
-        let _ = wait_attach_until_close(|process, module, image, config| {
-            loop {
-                if SHOULD_TERMINATE_MASHER.load(Ordering::SeqCst) {
-                    let res = toggle_overlay(false);
-                    if res.is_err() {
-                        log::error!("Failed to toggle masher overlay");
-                    }
-                    log::info!("Breaking mainloop in masher thread");
+/// The parts of a profile the masher runs with
+#[derive(Clone, Debug, PartialEq)]
+pub struct MasherConfig {
+    pub activation: Activation,
+    pub mash_timing: MashTiming,
+    pub mash_sequence: Vec<MashStep<Input>>,
+}
+
+/// What a `Masher` reports while it runs
+#[derive(Clone, Debug, PartialEq)]
+pub enum MasherEvent {
+    /// Found the game. `build` is the process name it was found by, see `GameReader::build`.
+    Attached {
+        build: &'static str,
+    },
+    Detached,
+    /// Whether the pointer chain to the dialogue box could be followed
+    DialoguePointer {
+        resolved: bool,
+    },
+    DialogueDetected,
+    DialogueClosed,
+    /// `GameManager.inputHandler.acceptingInput` changed
+    AcceptingInput {
+        accepting: bool,
+    },
+    MashingStarted,
+    /// `presses` is how many steps of the mash sequence were pressed
+    MashingStopped {
+        presses: u32,
+    },
+    /// Toggle mode disarmed itself after mashing through a dialogue
+    Deactivated,
+}
+
+type Subscribers = Arc<Mutex<Vec<Sender<MasherEvent>>>>;
+
+/// Controls a `Masher` running on another thread
+#[derive(Clone)]
+pub struct MasherHandle {
+    commands: Sender<MasherCommand>,
+    subscribers: Subscribers,
+}
+
+impl MasherHandle {
+    pub fn send(&self, command: MasherCommand) {
+        // Once the masher has stopped there is nothing left to control
+        let _ = self.commands.send(command);
+    }
+
+    /// Receives every event the masher reports from now on
+    pub fn subscribe(&self) -> Receiver<MasherEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .expect("Failed to get masher subscribers")
+            .push(sender);
+        receiver
+    }
+}
+
+/// Finds Hollow Knight and presses through its dialogues on a virtual controller
+pub struct Masher {
+    commands: Receiver<MasherCommand>,
+    subscribers: Subscribers,
+    config: MasherConfig,
+    /// Armed by the trigger chord, see `MasherCommand::Activate`
+    active: bool,
+    dialogue_resolved: bool,
+    dialogue_visible: bool,
+    accepting_input: bool,
+    shutting_down: bool,
+}
+
+impl Masher {
+    pub fn new(config: MasherConfig) -> (Masher, MasherHandle) {
+        let (commands, receiver) = mpsc::channel();
+        let subscribers = Subscribers::default();
+        let masher = Masher {
+            commands: receiver,
+            subscribers: Arc::clone(&subscribers),
+            config,
+            active: false,
+            dialogue_resolved: false,
+            dialogue_visible: false,
+            accepting_input: false,
+            shutting_down: false,
+        };
+        (
+            masher,
+            MasherHandle {
+                commands,
+                subscribers,
+            },
+        )
+    }
+
+    fn emit(&self, event: MasherEvent) {
+        log::debug!("Masher event: {:?}", event);
+        self.subscribers
+            .lock()
+            .expect("Failed to get masher subscribers")
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Applies every command sent since the last call
+    fn handle_commands(&mut self) {
+        loop {
+            match self.commands.try_recv() {
+                Ok(MasherCommand::Activate) => self.active = true,
+                Ok(MasherCommand::Deactivate) => self.active = false,
+                Ok(MasherCommand::ReloadConfig(config)) => self.config = config,
+                Ok(MasherCommand::Shutdown) | Err(TryRecvError::Disconnected) => {
+                    self.shutting_down = true;
                     return;
                 }
+                Err(TryRecvError::Empty) => return,
+            }
+        }
+    }
 
-                if IS_MASHER_ACTIVE.load(Ordering::SeqCst) {
-                    if let Some(err) = toggle_overlay(false).err() {
-                        log::error!("Failed to toggle masher overlay: {}", err);
-                        continue;
-                    }
-                    let Ok(module_address) = process.get_module_address(config.module_name) else {
-                        log::info!("Cannot attach to base module address");
-                        break;
-                    };
+    /// Whether the masher should be running, either by itself or because the chord armed it
+    fn is_enabled(&self) -> bool {
+        self.config.activation.mode == ActivationMode::Auto || self.active
+    }
+
+    /// Reports whatever changed since the last read of the game
+    fn update_game_state(&mut self, dialogue_resolved: bool, visible: bool, accepting: bool) {
+        if dialogue_resolved != self.dialogue_resolved {
+            self.dialogue_resolved = dialogue_resolved;
+            self.emit(MasherEvent::DialoguePointer {
+                resolved: dialogue_resolved,
+            });
+        }
+        if visible != self.dialogue_visible {
+            self.dialogue_visible = visible;
+            self.emit(if visible {
+                MasherEvent::DialogueDetected
+            } else {
+                MasherEvent::DialogueClosed
+            });
+        }
+        if accepting != self.accepting_input {
+            self.accepting_input = accepting;
+            self.emit(MasherEvent::AcceptingInput { accepting });
+        }
+    }
+
+    /// Runs until a `MasherCommand::Shutdown`, or until every handle is dropped.
+    /// `attach` looks for the game, see `HollowKnightState::attach`.
+    pub fn run<G: GameReader>(
+        mut self,
+        mut attach: impl FnMut() -> Option<G>,
+        pad: &mut dyn VirtualPad,
+        toggle_overlay: impl Fn(bool) -> Result<(), Box<dyn std::error::Error>>,
+    ) {
+        log::info!("TextMasher starting up");
 
-                    let base: Address = module_address + config.base_offset;
-                    let dialogue_box_opt = resolve_pointer_chain(
-                        &process,
-                        base,
-                        &config.pointer_chain,
-                        PointerSize::Bit64,
-                    );
-
-                    let input_pointer: UnityPointer<3> = UnityPointer::new(
-                        "GameManager",
-                        0,
-                        &[
-                            "_instance",
-                            "<inputHandler>k__BackingField",
-                            "acceptingInput",
-                        ],
-                    );
-
-                    let accepting_input: bool = input_pointer
-                        .deref(&process, &module, &image)
-                        .unwrap_or_default();
-
-                    if accepting_input {
-                        if let Some(dialogue_box_addr) = dialogue_box_opt {
-                            let mut next_time = Instant::now() + target_interval;
-                            let mut key_to_press = 0;
-
-                            if IS_MASHER_ACTIVE.load(Ordering::SeqCst)
-                                && matches!(process.read::<u8>(dialogue_box_addr + 0x2E), Ok(is_dialogue_hidden) if is_dialogue_hidden == 0)
-                            {
-                                do_key_event(100); // release keys
-                                while IS_MASHER_ACTIVE.load(Ordering::SeqCst)
-                                    && matches!(process.read::<u8>(dialogue_box_addr + 0x2E), Ok(is_dialogue_hidden) if is_dialogue_hidden == 0)
-                                {
-                                    let _ = toggle_overlay(true);
-                                    log::debug!("Trigger do key event: {}", key_to_press);
-                                    do_key_event(key_to_press);
-                                    key_to_press = (key_to_press + 1) % MAX_MASHING_KEY_COUNT;
-
-                                    // Calculate and wait for next interval
-                                    let now = Instant::now();
-                                    next_time += target_interval;
-                                    if next_time > now {
-                                        sleep(next_time - now);
-                                    }
+        loop {
+            self.handle_commands();
+            if self.shutting_down {
+                break;
+            }
+
+            log::info!("GameManagerFinder wait_attach...");
+            let Some(game) = attach() else {
+                sleep(Duration::from_millis(500));
+                continue;
+            };
+            self.emit(MasherEvent::Attached {
+                build: game.build(),
+            });
+
+            self.watch_game(&game, pad, &toggle_overlay);
+            if self.shutting_down {
+                log::info!("Breaking mainloop in masher thread");
+                break;
+            }
+
+            // Nothing is known about the next game until it has been read
+            self.dialogue_resolved = false;
+            self.dialogue_visible = false;
+            self.accepting_input = false;
+            self.emit(MasherEvent::Detached);
+        }
+    }
+
+    /// Reads the game and mashes through its dialogues until it closes or the masher shuts down
+    fn watch_game(
+        &mut self,
+        game: &impl GameReader,
+        pad: &mut dyn VirtualPad,
+        toggle_overlay: &impl Fn(bool) -> Result<(), Box<dyn std::error::Error>>,
+    ) {
+        let mut held: Vec<Input> = Vec::new();
+        // When auto mode first saw the open dialogue, and whether its conversation may be mashed
+        let mut auto_dialogue: Option<(Instant, bool)> = None;
+
+        loop {
+            self.handle_commands();
+            if self.shutting_down {
+                return;
+            }
+
+            if !self.is_enabled() {
+                auto_dialogue = None;
+            }
+
+            if !game.is_running() {
+                log::info!("Hollow Knight is no longer running");
+                return;
+            }
+
+            let dialogue = game.dialogue_visible();
+            let accepting_input = game.accepting_input();
+            self.update_game_state(dialogue.is_some(), dialogue == Some(true), accepting_input);
+
+            if self.is_enabled() && accepting_input {
+                if dialogue.is_some() {
+                    let visible = self.dialogue_visible;
+                    let settings = self.config.activation.clone();
+                    let should_mash = match settings.mode {
+                        ActivationMode::Auto if visible => {
+                            let (seen_at, allowed) = *auto_dialogue.get_or_insert_with(|| {
+                                let conversation = game.conversation();
+                                let allowed = settings.allows_conversation(conversation.as_deref());
+                                if !allowed {
+                                    log::info!(
+                                        "Not auto mashing conversation {:?} in scene {:?}",
+                                        conversation,
+                                        game.scene_name()
+                                    );
                                 }
-                                do_key_event(100);
-                                let _ = toggle_overlay(false);
-                            }
-                        } else {
-                            log::debug!("dialogue box not found");
+                                (Instant::now(), allowed)
+                            });
+                            allowed
+                                && seen_at.elapsed()
+                                    >= Duration::from_millis(settings.auto_start_delay_ms)
+                        }
+                        _ => {
+                            auto_dialogue = None;
+                            visible && self.active
                         }
+                    };
+
+                    if should_mash {
+                        self.mash_dialogue(game, pad, &mut held, toggle_overlay);
+                        auto_dialogue = None;
                     }
+                } else {
+                    log::debug!("dialogue box not found");
+                }
+            }
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Runs the mash sequence until the dialogue closes or the masher is disabled
+    fn mash_dialogue(
+        &mut self,
+        game: &impl GameReader,
+        pad: &mut dyn VirtualPad,
+        held: &mut Vec<Input>,
+        toggle_overlay: &impl Fn(bool) -> Result<(), Box<dyn std::error::Error>>,
+    ) {
+        release_all(pad, held);
+        self.emit(MasherEvent::MashingStarted);
+        show_overlay(toggle_overlay, true);
+        let mut step_index = 0;
+        let mut presses = 0;
+        let mut next_time = Instant::now();
+        loop {
+            self.handle_commands();
+            if self.shutting_down || !self.is_enabled() || game.dialogue_visible() != Some(true) {
+                break;
+            }
+
+            // Commands are applied every step so changes from the GUI apply immediately
+            let timing = self.config.mash_timing;
+            let (step, repeats_next) = {
+                let sequence = &self.config.mash_sequence;
+                if sequence.is_empty() {
+                    log::warn!("Mash sequence is empty, nothing to press");
+                    break;
+                }
+                step_index %= sequence.len();
+                let step = sequence[step_index].clone();
+                let next = &sequence[(step_index + 1) % sequence.len()];
+                let repeats_next = step
+                    .buttons
+                    .iter()
+                    .any(|input| next.buttons.contains(input));
+                (step, repeats_next)
+            };
+            step_index += 1;
+
+            let step_duration = timing.press_interval() * step.ticks.max(1);
+            let mut hold_duration = step_duration.mul_f64(timing.duty_cycle);
+            if repeats_next {
+                hold_duration = hold_duration.min(step_duration.mul_f64(1.0 - MIN_REPEAT_RELEASE));
+            }
+
+            log::debug!("Trigger do key event: {:?}", step.buttons);
+            let press_time = Instant::now();
+            presses += 1;
+            if let Err(err) = hold_only(pad, held, &step.buttons) {
+                log::error!("Failed to update virtual controller while mashing: {}", err);
+            }
+
+            if hold_duration < step_duration {
+                sleep_until(press_time + hold_duration);
+                if let Err(err) = hold_only(pad, held, &[]) {
+                    log::error!("Failed to update virtual controller while mashing: {}", err);
+                }
+            }
+
+            // Calculate and wait for next interval
+            next_time += step_duration + Duration::from_millis(timing.button_gap_ms);
+            sleep_until(next_time);
+        }
+        release_all(pad, held);
+        show_overlay(toggle_overlay, false);
+        self.emit(MasherEvent::MashingStopped { presses });
+
+        // Still active means the dialogue closed rather than the chord being released
+        if self.config.activation.mode == ActivationMode::Toggle
+            && self.active
+            && !self.shutting_down
+        {
+            log::info!("Dialogue closed, disarming the masher");
+            self.active = false;
+            self.emit(MasherEvent::Deactivated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hollow_knight::GameState;
+    use crate::virtual_pad::{PadEvent, RecorderPad};
+    use sdl3::gamepad::Button;
+    use std::error::Error;
+    use std::sync::MutexGuard;
+    use std::thread::{self, JoinHandle};
+
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// What the fake game reports, changed by the tests while the masher runs
+    struct FakeState {
+        running: bool,
+        dialogue_visible: bool,
+        accepting_input: bool,
+        conversation: Option<String>,
+    }
+
+    #[derive(Clone)]
+    struct FakeGame(Arc<Mutex<FakeState>>);
+
+    impl FakeGame {
+        fn new(dialogue_visible: bool) -> Self {
+            FakeGame(Arc::new(Mutex::new(FakeState {
+                running: true,
+                dialogue_visible,
+                accepting_input: true,
+                conversation: None,
+            })))
+        }
+
+        fn state(&self) -> MutexGuard<'_, FakeState> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    impl GameReader for FakeGame {
+        fn build(&self) -> &'static str {
+            "fake"
+        }
+
+        fn is_running(&self) -> bool {
+            self.state().running
+        }
+
+        fn dialogue_visible(&self) -> Option<bool> {
+            Some(self.state().dialogue_visible)
+        }
+
+        fn accepting_input(&self) -> bool {
+            self.state().accepting_input
+        }
+
+        fn conversation(&self) -> Option<String> {
+            self.state().conversation.clone()
+        }
+
+        fn scene_name(&self) -> Option<String> {
+            Some("Town".to_string())
+        }
+
+        fn game_state(&self) -> Option<GameState> {
+            Some(GameState::Playing)
+        }
+    }
+
+    fn south() -> Input {
+        Input::Button(Button::South)
+    }
+
+    fn west() -> Input {
+        Input::Button(Button::West)
+    }
+
+    fn config(mode: ActivationMode) -> MasherConfig {
+        MasherConfig {
+            activation: Activation {
+                mode,
+                ..Activation::default()
+            },
+            mash_timing: MashTiming {
+                rate: 100.0,
+                duty_cycle: 0.5,
+                button_gap_ms: 0,
+            },
+            mash_sequence: vec![
+                MashStep {
+                    buttons: vec![south()],
+                    ticks: 1,
+                },
+                MashStep {
+                    buttons: vec![west()],
+                    ticks: 1,
+                },
+            ],
+        }
+    }
+
+    /// A masher running against `FakeGame` on its own thread
+    struct RunningMasher {
+        handle: MasherHandle,
+        events: Receiver<MasherEvent>,
+        thread: JoinHandle<RecorderPad>,
+    }
+
+    impl RunningMasher {
+        fn start(config: MasherConfig, game: &FakeGame) -> Self {
+            Self::start_with_overlay(config, game, |_| Ok(()))
+        }
+
+        fn start_with_overlay(
+            config: MasherConfig,
+            game: &FakeGame,
+            toggle_overlay: fn(bool) -> Result<(), Box<dyn Error>>,
+        ) -> Self {
+            let (masher, handle) = Masher::new(config);
+            let events = handle.subscribe();
+            let game = game.clone();
+            let thread = thread::spawn(move || {
+                let mut pad = RecorderPad::default();
+                let attach = || game.is_running().then(|| game.clone());
+                masher.run(attach, &mut pad, toggle_overlay);
+                pad
+            });
+            RunningMasher {
+                handle,
+                events,
+                thread,
+            }
+        }
+
+        /// Waits for an event `matches` accepts, skipping any others on the way
+        fn wait_for(&self, matches: impl Fn(&MasherEvent) -> bool) -> MasherEvent {
+            let deadline = Instant::now() + EVENT_TIMEOUT;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match self.events.recv_timeout(remaining) {
+                    Ok(event) if matches(&event) => return event,
+                    Ok(_) => {}
+                    Err(err) => panic!("Masher didn't report the expected event: {}", err),
                 }
-                sleep(Duration::from_millis(100));
             }
-        });
+        }
+
+        /// Checks that nothing `matches` accepts is reported for a while
+        fn assert_no_event(&self, matches: impl Fn(&MasherEvent) -> bool, wait: Duration) {
+            let deadline = Instant::now() + wait;
+            while let Ok(event) = self
+                .events
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                assert!(!matches(&event), "Unexpected masher event {:?}", event);
+            }
+        }
+
+        fn shutdown(self) -> RecorderPad {
+            self.handle.send(MasherCommand::Shutdown);
+            self.thread.join().expect("Masher thread panicked")
+        }
+    }
+
+    fn presses(event: MasherEvent) -> u32 {
+        match event {
+            MasherEvent::MashingStopped { presses } => presses,
+            other => panic!("Expected MashingStopped, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn activate_mashes_until_deactivated() {
+        let game = FakeGame::new(true);
+        let masher = RunningMasher::start(config(ActivationMode::Hold), &game);
+        masher.wait_for(|event| matches!(event, MasherEvent::Attached { build: "fake" }));
+        masher.assert_no_event(
+            |event| *event == MasherEvent::MashingStarted,
+            Duration::from_millis(300),
+        );
+
+        masher.handle.send(MasherCommand::Activate);
+        masher.wait_for(|event| *event == MasherEvent::MashingStarted);
+        sleep(Duration::from_millis(100));
+        masher.handle.send(MasherCommand::Deactivate);
+        let stopped = masher.wait_for(|event| matches!(event, MasherEvent::MashingStopped { .. }));
+        assert!(presses(stopped) > 0);
+
+        // Hold mode leaves disarming to whoever sent Activate
+        masher.assert_no_event(
+            |event| {
+                matches!(
+                    event,
+                    MasherEvent::Deactivated | MasherEvent::MashingStarted
+                )
+            },
+            Duration::from_millis(300),
+        );
+        masher.shutdown();
+    }
+
+    /// Mashes one dialogue with `config` and returns what the masher sent to the pad,
+    /// along with the number of presses it reported
+    fn record_mash(config: MasherConfig) -> (Vec<PadEvent>, u32) {
+        let game = FakeGame::new(true);
+        let masher = RunningMasher::start(config, &game);
+        masher.handle.send(MasherCommand::Activate);
+        masher.wait_for(|event| *event == MasherEvent::MashingStarted);
+        sleep(Duration::from_millis(100));
+
+        game.state().dialogue_visible = false;
+        let stopped = masher.wait_for(|event| matches!(event, MasherEvent::MashingStopped { .. }));
+        (masher.shutdown().events().to_vec(), presses(stopped))
+    }
+
+    #[test]
+    fn mash_sequence_presses_and_releases_each_step() {
+        let (events, presses) = record_mash(config(ActivationMode::Hold));
+        assert!(presses >= 2);
+
+        let mut expected = vec![PadEvent::Reset];
+        for step in 0..presses {
+            let button = if step % 2 == 0 { south() } else { west() };
+            expected.extend([
+                PadEvent::Press(button),
+                PadEvent::Sync,
+                PadEvent::Release(button),
+                PadEvent::Sync,
+            ]);
+        }
+        expected.push(PadEvent::Reset);
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn full_duty_cycle_moves_straight_to_the_next_step() {
+        let mut config = config(ActivationMode::Hold);
+        config.mash_timing.duty_cycle = 1.0;
+        let (events, presses) = record_mash(config);
+        assert!(presses >= 2);
+
+        let mut expected = vec![PadEvent::Reset, PadEvent::Press(south()), PadEvent::Sync];
+        for step in 1..presses {
+            let (previous, button) = if step % 2 == 0 {
+                (west(), south())
+            } else {
+                (south(), west())
+            };
+            expected.extend([
+                PadEvent::Release(previous),
+                PadEvent::Press(button),
+                PadEvent::Sync,
+            ]);
+        }
+        expected.push(PadEvent::Reset);
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn full_duty_cycle_releases_a_button_pressed_again() {
+        let mut config = config(ActivationMode::Hold);
+        config.mash_timing.duty_cycle = 1.0;
+        config.mash_sequence = vec![MashStep {
+            buttons: vec![south()],
+            ticks: 1,
+        }];
+        let (events, presses) = record_mash(config);
+        assert!(presses >= 2);
+
+        let mut expected = vec![PadEvent::Reset];
+        for _ in 0..presses {
+            expected.extend([
+                PadEvent::Press(south()),
+                PadEvent::Sync,
+                PadEvent::Release(south()),
+                PadEvent::Sync,
+            ]);
+        }
+        expected.push(PadEvent::Reset);
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn step_buttons_are_held_together() {
+        let mut config = config(ActivationMode::Hold);
+        config.mash_sequence = vec![MashStep {
+            buttons: vec![south(), west()],
+            ticks: 2,
+        }];
+        let (events, presses) = record_mash(config);
+        assert!(presses >= 1);
+
+        let mut expected = vec![PadEvent::Reset];
+        for _ in 0..presses {
+            expected.extend([
+                PadEvent::Press(south()),
+                PadEvent::Press(west()),
+                PadEvent::Sync,
+                PadEvent::Release(south()),
+                PadEvent::Release(west()),
+                PadEvent::Sync,
+            ]);
+        }
+        expected.push(PadEvent::Reset);
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn toggle_mode_disarms_when_the_dialogue_closes() {
+        let game = FakeGame::new(true);
+        let masher = RunningMasher::start(config(ActivationMode::Toggle), &game);
+        masher.handle.send(MasherCommand::Activate);
+        masher.wait_for(|event| *event == MasherEvent::MashingStarted);
+
+        game.state().dialogue_visible = false;
+        masher.wait_for(|event| matches!(event, MasherEvent::MashingStopped { .. }));
+        masher.wait_for(|event| *event == MasherEvent::Deactivated);
+        masher.wait_for(|event| *event == MasherEvent::DialogueClosed);
+
+        // Disarmed, so the next dialogue is left alone
+        game.state().dialogue_visible = true;
+        masher.wait_for(|event| *event == MasherEvent::DialogueDetected);
+        masher.assert_no_event(
+            |event| *event == MasherEvent::MashingStarted,
+            Duration::from_millis(300),
+        );
+        masher.shutdown();
+    }
+
+    #[test]
+    fn auto_mode_waits_for_the_start_delay() {
+        let game = FakeGame::new(false);
+        let mut config = config(ActivationMode::Auto);
+        config.activation.auto_start_delay_ms = 300;
+        // The overlay is optional, auto mode has to mash without it
+        let masher = RunningMasher::start_with_overlay(config, &game, |_| Err("no overlay".into()));
+        masher.wait_for(|event| matches!(event, MasherEvent::Attached { .. }));
+
+        game.state().dialogue_visible = true;
+        let opened = Instant::now();
+        masher.wait_for(|event| *event == MasherEvent::DialogueDetected);
+        masher.wait_for(|event| *event == MasherEvent::MashingStarted);
+        assert!(opened.elapsed() >= Duration::from_millis(300));
+
+        game.state().dialogue_visible = false;
+        masher.wait_for(|event| matches!(event, MasherEvent::MashingStopped { .. }));
+        masher.shutdown();
+    }
+
+    #[test]
+    fn auto_mode_only_mashes_allowed_conversations() {
+        let game = FakeGame::new(false);
+        game.state().conversation = Some("SLY_MAIN".to_string());
+        let mut config = config(ActivationMode::Auto);
+        config.activation.auto_conversations = vec!["ELDERBUG_*".to_string()];
+        let masher = RunningMasher::start(config, &game);
+        masher.wait_for(|event| matches!(event, MasherEvent::Attached { .. }));
+
+        game.state().dialogue_visible = true;
+        masher.wait_for(|event| *event == MasherEvent::DialogueDetected);
+        masher.assert_no_event(
+            |event| *event == MasherEvent::MashingStarted,
+            Duration::from_millis(300),
+        );
+
+        game.state().dialogue_visible = false;
+        masher.wait_for(|event| *event == MasherEvent::DialogueClosed);
+        {
+            let mut state = game.state();
+            state.conversation = Some("ELDERBUG_INTRO_MAIN".to_string());
+            state.dialogue_visible = true;
+        }
+        masher.wait_for(|event| *event == MasherEvent::MashingStarted);
+        masher.shutdown();
+    }
+
+    #[test]
+    fn shutdown_stops_mashing_and_releases_everything() {
+        let game = FakeGame::new(true);
+        let masher = RunningMasher::start(config(ActivationMode::Hold), &game);
+        masher.handle.send(MasherCommand::Activate);
+        masher.wait_for(|event| *event == MasherEvent::MashingStarted);
+
+        masher.handle.send(MasherCommand::Shutdown);
+        masher.wait_for(|event| matches!(event, MasherEvent::MashingStopped { .. }));
+        let pad = masher.shutdown();
+        assert_eq!(pad.events().last(), Some(&PadEvent::Reset));
+    }
+
+    #[test]
+    fn shutdown_while_waiting_for_the_game() {
+        let game = FakeGame::new(false);
+        game.state().running = false;
+        let masher = RunningMasher::start(config(ActivationMode::Hold), &game);
+        let pad = masher.shutdown();
+        assert!(pad.events().is_empty());
+    }
+
+    #[test]
+    fn reports_when_the_game_closes() {
+        let game = FakeGame::new(false);
+        let masher = RunningMasher::start(config(ActivationMode::Hold), &game);
+        masher.wait_for(|event| matches!(event, MasherEvent::Attached { .. }));
+
+        game.state().running = false;
+        masher.wait_for(|event| *event == MasherEvent::Detached);
+        masher.shutdown();
     }
 }
@@ -0,0 +1,776 @@
+use crate::settings::Input;
+use log::debug;
+use sdl3::gamepad;
+use std::error::Error;
+#[cfg(target_os = "windows")]
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(target_os = "windows")]
+use vigem_client::XButtons;
+#[cfg(target_os = "linux")]
+use {
+    uinput::event::absolute::{Hat, Position},
+    uinput::event::controller::GamePad,
+    uinput::event::Controller,
+    uinput::Event::Absolute,
+};
+
+pub type PadResult = Result<(), Box<dyn Error>>;
+
+/// Name prefix of the virtual devices HKCM creates
+const VIRTUAL_DEVICE_PREFIX: &str = "Overbind Virtual";
+
+/// USB ids HKCM gives its uinput devices so they can be told apart from real ones.
+/// The vendor is pid.codes' id for open source hardware.
+const VIRTUAL_DEVICE_VENDOR: u16 = 0x1209;
+const VIRTUAL_PAD_PRODUCT: u16 = 0x4843;
+const VIRTUAL_KEYBOARD_PRODUCT: u16 = 0x4844;
+
+/// Whether a device is one HKCM created, going by its name or USB ids.
+/// Their inputs must never count towards a trigger chord, or the masher could trigger itself.
+pub fn is_virtual_device(name: Option<&str>, vendor: Option<u16>, product: Option<u16>) -> bool {
+    let own_ids = vendor == Some(VIRTUAL_DEVICE_VENDOR)
+        && matches!(product, Some(id) if id == VIRTUAL_PAD_PRODUCT || id == VIRTUAL_KEYBOARD_PRODUCT);
+    own_ids || name.is_some_and(|name| name.starts_with(VIRTUAL_DEVICE_PREFIX))
+}
+
+/// XInput slot of HKCM's ViGEm pad, `u32::MAX` until it is plugged in
+#[cfg(target_os = "windows")]
+static VIGEM_USER_INDEX: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Whether an SDL gamepad is HKCM's ViGEm pad, going by its player index, which SDL takes
+/// from the XInput slot. The pad keeps the stock Xbox 360 ids so games map it like a real
+/// one, which leaves nothing else to tell it apart by.
+#[cfg(target_os = "windows")]
+pub fn is_vigem_pad(player_index: Option<u16>) -> bool {
+    player_index.is_some_and(|index| u32::from(index) == VIGEM_USER_INDEX.load(Ordering::Relaxed))
+}
+
+/// Whether an SDL gamepad is HKCM's ViGEm pad, which only exists on Windows
+#[cfg(not(target_os = "windows"))]
+pub fn is_vigem_pad(_player_index: Option<u16>) -> bool {
+    false
+}
+
+/// A controller the masher can send inputs to.
+///
+/// Presses and releases may be buffered until `sync` is called, so a whole
+/// step of a mash sequence reaches the game at once.
+pub trait VirtualPad {
+    /// Holds `input` down until it is released. Inputs the backend can't send are ignored.
+    fn press(&mut self, input: Input) -> PadResult;
+
+    fn release(&mut self, input: Input) -> PadResult;
+
+    /// Moves an analog axis. Sticks go from -1 to 1 and triggers from 0 to 1.
+    fn set_axis(&mut self, axis: gamepad::Axis, value: f32) -> PadResult;
+
+    /// Sends every press and release since the last sync
+    fn sync(&mut self) -> PadResult;
+
+    /// Releases everything that is held and syncs
+    fn reset(&mut self) -> PadResult;
+}
+
+/// Presses `inputs` and releases anything else in `held`, then syncs.
+/// `held` is updated to match what the pad is now holding.
+pub fn hold_only(pad: &mut dyn VirtualPad, held: &mut Vec<Input>, inputs: &[Input]) -> PadResult {
+    for input in held.iter().filter(|input| !inputs.contains(input)) {
+        pad.release(*input)?;
+    }
+    for input in inputs.iter().filter(|input| !held.contains(input)) {
+        pad.press(*input)?;
+    }
+    *held = inputs.to_vec();
+    pad.sync()
+}
+
+/// Creates the virtual controller for the current platform
+#[cfg(target_os = "linux")]
+pub fn platform_pad() -> Result<Box<dyn VirtualPad>, Box<dyn Error>> {
+    Ok(Box::new(UinputPad::new()?))
+}
+
+/// Creates the virtual controller for the current platform
+#[cfg(target_os = "windows")]
+pub fn platform_pad() -> Result<Box<dyn VirtualPad>, Box<dyn Error>> {
+    Ok(Box::new(VigemPad::new()?))
+}
+
+/// Creates the virtual controller physical controllers are passed through in proxy mode
+#[cfg(target_os = "linux")]
+pub fn proxy_pad() -> Result<Box<dyn VirtualPad + Send>, Box<dyn Error>> {
+    Ok(Box::new(UinputPad::new()?))
+}
+
+/// Creates the virtual controller physical controllers are passed through in proxy mode
+#[cfg(not(target_os = "linux"))]
+pub fn proxy_pad() -> Result<Box<dyn VirtualPad + Send>, Box<dyn Error>> {
+    Err("proxy mode is only supported on Linux".into())
+}
+
+/// Who is driving a `SharedPad`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PadSource {
+    Masher,
+    /// Inputs passed through from physical controllers
+    Proxy,
+}
+
+impl PadSource {
+    fn other(self) -> PadSource {
+        match self {
+            PadSource::Masher => PadSource::Proxy,
+            PadSource::Proxy => PadSource::Masher,
+        }
+    }
+}
+
+struct SharedPadState {
+    pad: Box<dyn VirtualPad + Send>,
+    /// What each `PadSource` is holding, indexed by source
+    held: [Vec<Input>; 2],
+    /// Last value the proxy gave each axis, to go back to once the masher lets go of a trigger
+    proxy_axes: Vec<(gamepad::Axis, f32)>,
+    /// Only the masher reaches the game, see `SharedPad::set_masher_exclusive`
+    masher_exclusive: bool,
+}
+
+impl SharedPadState {
+    fn held(&mut self, source: PadSource) -> &mut Vec<Input> {
+        &mut self.held[source as usize]
+    }
+
+    /// Whether `source` holding `input` is sent to the game
+    fn is_sent(&mut self, source: PadSource, input: Input) -> bool {
+        (source == PadSource::Masher || !self.masher_exclusive)
+            && self.held(source).contains(&input)
+    }
+
+    /// Whether the masher is holding the trigger behind `axis` down
+    fn is_masher_pulling(&mut self, axis: gamepad::Axis) -> bool {
+        self.held(PadSource::Masher)
+            .iter()
+            .any(|input| input.trigger_axis() == Some(axis))
+    }
+
+    /// Sends where the proxy has an axis, or where it would be with nobody touching it
+    fn send_proxy_axis(&mut self, axis: gamepad::Axis, value: f32) -> PadResult {
+        if self.is_masher_pulling(axis) {
+            return Ok(());
+        }
+        let value = if self.masher_exclusive { 0.0 } else { value };
+        self.pad.set_axis(axis, value)
+    }
+
+    fn release(&mut self, source: PadSource, input: Input) -> PadResult {
+        if !self.held(source).contains(&input) {
+            return Ok(());
+        }
+        let was_sent = self.is_sent(source, input);
+        self.held(source).retain(|held| *held != input);
+        if !was_sent || self.is_sent(source.other(), input) {
+            return Ok(());
+        }
+
+        self.pad.release(input)?;
+        if let Some(axis) = input.trigger_axis() {
+            if let Some((_, value)) = self.proxy_axes.iter().find(|(moved, _)| *moved == axis) {
+                self.send_proxy_axis(axis, *value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One virtual controller driven by both the masher and the proxy.
+/// An input stays held while either of them is holding it, and a trigger the masher
+/// is pulling stays fully pressed whatever the physical trigger does.
+#[derive(Clone)]
+pub struct SharedPad {
+    state: Arc<Mutex<SharedPadState>>,
+    source: PadSource,
+}
+
+impl SharedPad {
+    /// Takes over `pad`, the returned handle drives it as the masher
+    pub fn new(pad: Box<dyn VirtualPad + Send>) -> Self {
+        SharedPad {
+            state: Arc::new(Mutex::new(SharedPadState {
+                pad,
+                held: [Vec::new(), Vec::new()],
+                proxy_axes: Vec::new(),
+                masher_exclusive: false,
+            })),
+            source: PadSource::Masher,
+        }
+    }
+
+    /// Another handle to the same controller, driving it as `source`
+    pub fn with_source(&self, source: PadSource) -> Self {
+        SharedPad {
+            state: Arc::clone(&self.state),
+            source,
+        }
+    }
+
+    /// While set, only the masher's inputs reach the game. Whatever the proxy is holding
+    /// is let go of and its sticks and triggers are centred, then put back once cleared.
+    /// The proxy can keep sending in the meantime, it just isn't passed on.
+    pub fn set_masher_exclusive(&self, exclusive: bool) -> PadResult {
+        let mut state = self.lock();
+        if state.masher_exclusive == exclusive {
+            return Ok(());
+        }
+
+        state.masher_exclusive = exclusive;
+        for input in state.held(PadSource::Proxy).clone() {
+            if state.held(PadSource::Masher).contains(&input) {
+                continue;
+            }
+            if exclusive {
+                state.pad.release(input)?;
+            } else {
+                state.pad.press(input)?;
+            }
+        }
+        for (axis, value) in state.proxy_axes.clone() {
+            state.send_proxy_axis(axis, value)?;
+        }
+        state.pad.sync()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SharedPadState> {
+        self.state
+            .lock()
+            .expect("Failed to lock the shared virtual controller")
+    }
+}
+
+impl VirtualPad for SharedPad {
+    fn press(&mut self, input: Input) -> PadResult {
+        let source = self.source;
+        let mut state = self.lock();
+        if state.held(source).contains(&input) {
+            return Ok(());
+        }
+        let already_sent = state.is_sent(source.other(), input);
+        state.held(source).push(input);
+        if already_sent || !state.is_sent(source, input) {
+            return Ok(());
+        }
+        state.pad.press(input)
+    }
+
+    fn release(&mut self, input: Input) -> PadResult {
+        let source = self.source;
+        self.lock().release(source, input)
+    }
+
+    fn set_axis(&mut self, axis: gamepad::Axis, value: f32) -> PadResult {
+        let mut state = self.lock();
+        match self.source {
+            PadSource::Proxy => {
+                state.proxy_axes.retain(|(moved, _)| *moved != axis);
+                state.proxy_axes.push((axis, value));
+                state.send_proxy_axis(axis, value)
+            }
+            PadSource::Masher => state.pad.set_axis(axis, value),
+        }
+    }
+
+    fn sync(&mut self) -> PadResult {
+        self.lock().pad.sync()
+    }
+
+    /// Only releases what this handle's source is holding
+    fn reset(&mut self) -> PadResult {
+        let source = self.source;
+        let mut state = self.lock();
+        for input in state.held(source).clone() {
+            state.release(source, input)?;
+        }
+        state.pad.sync()
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PadEvent {
+    Press(Input),
+    Release(Input),
+    Axis(gamepad::Axis, f32),
+    Sync,
+    Reset,
+}
+
+/// Keeps every call in memory instead of driving a controller, for tests.
+/// Clones share one recording, so a test can keep one while a `SharedPad` owns another.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct RecorderPad {
+    events: Arc<Mutex<Vec<PadEvent>>>,
+}
+
+#[cfg(test)]
+impl RecorderPad {
+    pub fn events(&self) -> Vec<PadEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Forgets everything recorded so far
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    fn record(&mut self, event: PadEvent) -> PadResult {
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl VirtualPad for RecorderPad {
+    fn press(&mut self, input: Input) -> PadResult {
+        self.record(PadEvent::Press(input))
+    }
+
+    fn release(&mut self, input: Input) -> PadResult {
+        self.record(PadEvent::Release(input))
+    }
+
+    fn set_axis(&mut self, axis: gamepad::Axis, value: f32) -> PadResult {
+        self.record(PadEvent::Axis(axis, value))
+    }
+
+    fn sync(&mut self) -> PadResult {
+        self.record(PadEvent::Sync)
+    }
+
+    fn reset(&mut self) -> PadResult {
+        self.record(PadEvent::Reset)
+    }
+}
+
+/// How an input is sent through the uinput gamepad
+#[cfg(target_os = "linux")]
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum UinputOutput {
+    Button(Controller),
+    /// A d-pad direction, one end of a hat axis
+    Hat(Hat, i32),
+    /// An analog trigger, sent fully pressed
+    Trigger(Position),
+}
+
+#[cfg(target_os = "linux")]
+fn input_to_uinput(input: Input) -> Option<UinputOutput> {
+    let button = match input {
+        Input::LeftTrigger => return Some(UinputOutput::Trigger(Position::Z)),
+        Input::RightTrigger => return Some(UinputOutput::Trigger(Position::RZ)),
+        Input::Button(button) => button,
+        Input::Key(_) | Input::Stick { .. } => return None,
+    };
+
+    let gamepad_button = |button| Some(UinputOutput::Button(Controller::GamePad(button)));
+    match button {
+        gamepad::Button::North => gamepad_button(GamePad::North),
+        gamepad::Button::East => gamepad_button(GamePad::East),
+        gamepad::Button::South => gamepad_button(GamePad::South),
+        gamepad::Button::West => gamepad_button(GamePad::West),
+        gamepad::Button::Back => gamepad_button(GamePad::Select),
+        gamepad::Button::Guide => gamepad_button(GamePad::Mode),
+        gamepad::Button::Start => gamepad_button(GamePad::Start),
+        gamepad::Button::LeftStick => gamepad_button(GamePad::ThumbL),
+        gamepad::Button::RightStick => gamepad_button(GamePad::ThumbR),
+        gamepad::Button::LeftShoulder => gamepad_button(GamePad::TL),
+        gamepad::Button::RightShoulder => gamepad_button(GamePad::TR),
+        gamepad::Button::DPadUp => Some(UinputOutput::Hat(Hat::Y0, -1)),
+        gamepad::Button::DPadDown => Some(UinputOutput::Hat(Hat::Y0, 1)),
+        gamepad::Button::DPadLeft => Some(UinputOutput::Hat(Hat::X0, -1)),
+        gamepad::Button::DPadRight => Some(UinputOutput::Hat(Hat::X0, 1)),
+        _ => None, // paddles and the like have no standard evdev code
+    }
+}
+
+/// `EV_KEY` from `linux/input-event-codes.h`
+#[cfg(target_os = "linux")]
+const EV_KEY: i32 = 0x01;
+
+/// Range of the `Z` and `RZ` trigger axes
+#[cfg(target_os = "linux")]
+const TRIGGER_MAX: i32 = 1023;
+
+/// The uinput axis for each gamepad axis, with the raw value at full travel
+#[cfg(target_os = "linux")]
+fn axis_to_uinput(axis: gamepad::Axis) -> (Position, i32) {
+    match axis {
+        gamepad::Axis::LeftX => (Position::X, 32767),
+        gamepad::Axis::LeftY => (Position::Y, 32767),
+        gamepad::Axis::RightX => (Position::RX, 32767),
+        gamepad::Axis::RightY => (Position::RY, 32767),
+        gamepad::Axis::TriggerLeft => (Position::Z, TRIGGER_MAX),
+        gamepad::Axis::TriggerRight => (Position::RZ, TRIGGER_MAX),
+    }
+}
+
+/// The "Overbind Virtual Gamepad" uinput device, along with a virtual keyboard
+/// for runners who have their actions bound to keys
+#[cfg(target_os = "linux")]
+pub struct UinputPad {
+    device: uinput::Device,
+    keyboard: uinput::Device,
+    pressed: Vec<UinputOutput>,
+    pressed_keys: Vec<u16>,
+}
+
+#[cfg(target_os = "linux")]
+impl UinputPad {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let device = uinput::default()?
+            .name("Overbind Virtual Gamepad")?
+            .vendor(VIRTUAL_DEVICE_VENDOR)
+            .product(VIRTUAL_PAD_PRODUCT)
+            .event(Absolute(uinput::event::absolute::Absolute::Position(
+                Position::X,
+            )))?
+            .min(-32768)
+            .max(32767)
+            .fuzz(0)
+            .flat(0)
+            .event(Absolute(uinput::event::absolute::Absolute::Position(
+                Position::Y,
+            )))?
+            .min(-32768)
+            .max(32767)
+            .fuzz(0)
+            .flat(0)
+            .event(Absolute(uinput::event::absolute::Absolute::Position(
+                Position::RX,
+            )))?
+            .min(-32768)
+            .max(32767)
+            .fuzz(0)
+            .flat(0)
+            .event(Absolute(uinput::event::absolute::Absolute::Position(
+                Position::RY,
+            )))?
+            .min(-32768)
+            .max(32767)
+            .fuzz(0)
+            .flat(0)
+            .event(Absolute(uinput::event::absolute::Absolute::Hat(Hat::X0)))?
+            .min(-1)
+            .max(1)
+            .fuzz(0)
+            .flat(0)
+            .event(Absolute(uinput::event::absolute::Absolute::Hat(Hat::Y0)))?
+            .min(-1)
+            .max(1)
+            .fuzz(0)
+            .flat(0)
+            .event(Absolute(uinput::event::absolute::Absolute::Position(
+                Position::Z,
+            )))?
+            .min(0)
+            .max(TRIGGER_MAX)
+            .fuzz(0)
+            .flat(0)
+            .event(Absolute(uinput::event::absolute::Absolute::Position(
+                Position::RZ,
+            )))?
+            .min(0)
+            .max(TRIGGER_MAX)
+            .fuzz(0)
+            .flat(0)
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::North,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::South,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::East,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::West,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(GamePad::TL)))?
+            .event(uinput::Event::Controller(Controller::GamePad(GamePad::TR)))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::ThumbL,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::ThumbR,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::Select,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::Start,
+            )))?
+            .event(uinput::Event::Controller(Controller::GamePad(
+                GamePad::Mode,
+            )))?
+            .create()?;
+
+        let keyboard = uinput::default()?
+            .name("Overbind Virtual Keyboard")?
+            .vendor(VIRTUAL_DEVICE_VENDOR)
+            .product(VIRTUAL_KEYBOARD_PRODUCT)
+            .event(uinput::event::Keyboard::All)?
+            .create()?;
+
+        Ok(UinputPad {
+            device,
+            keyboard,
+            pressed: Vec::new(),
+            pressed_keys: Vec::new(),
+        })
+    }
+
+    /// Where a hat axis should sit for the d-pad directions being held.
+    /// Opposite directions cancel out.
+    fn hat_value(&self, hat: Hat) -> i32 {
+        self.pressed
+            .iter()
+            .map(|output| match output {
+                UinputOutput::Hat(pressed_hat, value) if *pressed_hat == hat => *value,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn send(&mut self, output: UinputOutput, pressed: bool) -> PadResult {
+        match output {
+            UinputOutput::Button(button) if pressed => self.device.press(&button)?,
+            UinputOutput::Button(button) => self.device.release(&button)?,
+            UinputOutput::Hat(hat, _) => {
+                let value = self.hat_value(hat);
+                self.device
+                    .send(Absolute(uinput::event::absolute::Absolute::Hat(hat)), value)?
+            }
+            UinputOutput::Trigger(position) => {
+                let value = if pressed { TRIGGER_MAX } else { 0 };
+                self.device.send(
+                    Absolute(uinput::event::absolute::Absolute::Position(position)),
+                    value,
+                )?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl VirtualPad for UinputPad {
+    fn press(&mut self, input: Input) -> PadResult {
+        if let Input::Key(code) = input {
+            if !self.pressed_keys.contains(&code) {
+                self.keyboard.write(EV_KEY, code as i32, 1)?;
+                self.pressed_keys.push(code);
+            }
+            return Ok(());
+        }
+
+        let Some(output) = input_to_uinput(input) else {
+            debug!("{} can't be sent with uinput", input.name());
+            return Ok(());
+        };
+        if !self.pressed.contains(&output) {
+            self.pressed.push(output);
+            self.send(output, true)?;
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, input: Input) -> PadResult {
+        if let Input::Key(code) = input {
+            if self.pressed_keys.contains(&code) {
+                self.keyboard.write(EV_KEY, code as i32, 0)?;
+                self.pressed_keys.retain(|pressed| *pressed != code);
+            }
+            return Ok(());
+        }
+
+        let Some(output) = input_to_uinput(input) else {
+            return Ok(());
+        };
+        if self.pressed.contains(&output) {
+            self.pressed.retain(|pressed| *pressed != output);
+            self.send(output, false)?;
+        }
+        Ok(())
+    }
+
+    fn set_axis(&mut self, axis: gamepad::Axis, value: f32) -> PadResult {
+        let (position, max) = axis_to_uinput(axis);
+        let value = (value * max as f32).round() as i32;
+        self.device.send(
+            Absolute(uinput::event::absolute::Absolute::Position(position)),
+            value,
+        )?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> PadResult {
+        self.device.synchronize()?;
+        self.keyboard.synchronize()?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> PadResult {
+        for output in std::mem::take(&mut self.pressed) {
+            self.send(output, false)?;
+        }
+        for code in self.pressed_keys.drain(..) {
+            self.keyboard.write(EV_KEY, code as i32, 0)?;
+        }
+        self.sync()
+    }
+}
+
+#[cfg(target_os = "windows")]
+#[derive(PartialEq, Debug, Clone, Hash, Eq)]
+enum VigemInput {
+    Button(u16),
+    LeftTrigger,
+    RightTrigger,
+}
+
+#[cfg(target_os = "windows")]
+fn input_to_vigem(input: Input) -> Option<VigemInput> {
+    let button = match input {
+        Input::LeftTrigger => return Some(VigemInput::LeftTrigger),
+        Input::RightTrigger => return Some(VigemInput::RightTrigger),
+        Input::Button(button) => button,
+        Input::Key(_) | Input::Stick { .. } => return None,
+    };
+
+    match button {
+        gamepad::Button::North => Some(VigemInput::Button(XButtons::Y)),
+        gamepad::Button::East => Some(VigemInput::Button(XButtons::B)),
+        gamepad::Button::South => Some(VigemInput::Button(XButtons::A)),
+        gamepad::Button::West => Some(VigemInput::Button(XButtons::X)),
+        gamepad::Button::Back => Some(VigemInput::Button(XButtons::BACK)),
+        gamepad::Button::Guide => Some(VigemInput::Button(XButtons::GUIDE)),
+        gamepad::Button::Start => Some(VigemInput::Button(XButtons::START)),
+        gamepad::Button::LeftStick => Some(VigemInput::Button(XButtons::LTHUMB)),
+        gamepad::Button::RightStick => Some(VigemInput::Button(XButtons::RTHUMB)),
+        gamepad::Button::LeftShoulder => Some(VigemInput::Button(XButtons::LB)),
+        gamepad::Button::RightShoulder => Some(VigemInput::Button(XButtons::RB)),
+        gamepad::Button::DPadUp => Some(VigemInput::Button(XButtons::UP)),
+        gamepad::Button::DPadDown => Some(VigemInput::Button(XButtons::DOWN)),
+        gamepad::Button::DPadLeft => Some(VigemInput::Button(XButtons::LEFT)),
+        gamepad::Button::DPadRight => Some(VigemInput::Button(XButtons::RIGHT)),
+        _ => None, // not supported in vigem
+    }
+}
+
+/// A ViGEm Xbox 360 controller
+#[cfg(target_os = "windows")]
+pub struct VigemPad {
+    target: vigem_client::Xbox360Wired<vigem_client::Client>,
+    state: vigem_client::XGamepad,
+}
+
+#[cfg(target_os = "windows")]
+impl VigemPad {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let client = vigem_client::Client::connect()?;
+        let id = vigem_client::TargetId::XBOX360_WIRED;
+        let mut target = vigem_client::Xbox360Wired::new(client, id);
+        target.plugin()?;
+        target.wait_ready()?;
+        VIGEM_USER_INDEX.store(target.get_user_index()?, Ordering::Relaxed);
+
+        Ok(VigemPad {
+            target,
+            state: vigem_client::XGamepad::default(),
+        })
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl VirtualPad for VigemPad {
+    fn press(&mut self, input: Input) -> PadResult {
+        match input_to_vigem(input) {
+            Some(VigemInput::Button(b)) => self.state.buttons = XButtons(self.state.buttons.0 | b),
+            Some(VigemInput::LeftTrigger) => self.state.left_trigger = u8::MAX,
+            Some(VigemInput::RightTrigger) => self.state.right_trigger = u8::MAX,
+            None => debug!("{} can't be sent with ViGEm", input.name()),
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, input: Input) -> PadResult {
+        match input_to_vigem(input) {
+            Some(VigemInput::Button(b)) => self.state.buttons = XButtons(self.state.buttons.0 & !b),
+            Some(VigemInput::LeftTrigger) => self.state.left_trigger = 0,
+            Some(VigemInput::RightTrigger) => self.state.right_trigger = 0,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn set_axis(&mut self, axis: gamepad::Axis, value: f32) -> PadResult {
+        let stick = |value: f32| (value * i16::MAX as f32).round() as i16;
+        let trigger = (value * u8::MAX as f32).round() as u8;
+        // XInput sticks point up for positive Y, SDL's point down
+        match axis {
+            gamepad::Axis::LeftX => self.state.thumb_lx = stick(value),
+            gamepad::Axis::LeftY => self.state.thumb_ly = stick(-value),
+            gamepad::Axis::RightX => self.state.thumb_rx = stick(value),
+            gamepad::Axis::RightY => self.state.thumb_ry = stick(-value),
+            gamepad::Axis::TriggerLeft => self.state.left_trigger = trigger,
+            gamepad::Axis::TriggerRight => self.state.right_trigger = trigger,
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> PadResult {
+        self.target.update(&self.state)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> PadResult {
+        self.state = vigem_client::XGamepad::default();
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hold_only_releases_then_presses_then_syncs() {
+        let south = Input::Button(gamepad::Button::South);
+        let west = Input::Button(gamepad::Button::West);
+        let north = Input::Button(gamepad::Button::North);
+        let mut pad = RecorderPad::default();
+        let mut held = vec![south, west];
+
+        hold_only(&mut pad, &mut held, &[west, north]).unwrap();
+        assert_eq!(
+            pad.events(),
+            [
+                PadEvent::Release(south),
+                PadEvent::Press(north),
+                PadEvent::Sync
+            ]
+        );
+        assert_eq!(held, [west, north]);
+    }
+
+    #[test]
+    fn hold_only_with_nothing_to_change_only_syncs() {
+        let south = Input::Button(gamepad::Button::South);
+        let mut pad = RecorderPad::default();
+        let mut held = vec![south];
+
+        hold_only(&mut pad, &mut held, &[south]).unwrap();
+        assert_eq!(pad.events(), [PadEvent::Sync]);
+        assert_eq!(held, [south]);
+    }
+}